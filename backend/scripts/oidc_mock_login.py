@@ -0,0 +1,171 @@
+#!/usr/bin/env python3
+"""Checks the OIDC login against a mock provider.
+
+Start the server with this in data/settings.json (plus the usual settings):
+
+    "oidc": {
+      "issuer": "http://127.0.0.1:9000",
+      "client_id": "trainboard",
+      "redirect_uri": "http://127.0.0.1:3000/api/v1/auth/oidc/callback"
+    }
+
+then run `python3 scripts/oidc_mock_login.py [server URL]`. The mock provider serves
+discovery, JWKS and the token endpoint on port 9000 and needs the `cryptography` package.
+"""
+
+import base64
+import hashlib
+import json
+import sys
+import threading
+import time
+import urllib.error
+import urllib.parse
+import urllib.request
+from http.server import BaseHTTPRequestHandler, HTTPServer
+
+from cryptography.hazmat.primitives import hashes
+from cryptography.hazmat.primitives.asymmetric import padding, rsa
+
+ISSUER = "http://127.0.0.1:9000"
+SERVER = sys.argv[1].rstrip("/") if len(sys.argv) > 1 else "http://127.0.0.1:3000"
+LOGIN = SERVER + "/api/v1/auth/oidc/login"
+
+key = rsa.generate_private_key(public_exponent=65537, key_size=2048)
+codes = {}
+
+
+def b64(data):
+    return base64.urlsafe_b64encode(data).rstrip(b"=").decode()
+
+
+def uint(n):
+    return b64(n.to_bytes((n.bit_length() + 7) // 8, "big"))
+
+
+def sign(claims):
+    signing_input = b64(json.dumps({"alg": "RS256", "kid": "mock", "typ": "JWT"}).encode())
+    signing_input += "." + b64(json.dumps(claims).encode())
+    signature = key.sign(signing_input.encode(), padding.PKCS1v15(), hashes.SHA256())
+    return signing_input + "." + b64(signature)
+
+
+class Provider(BaseHTTPRequestHandler):
+    def send_json(self, body, status=200):
+        data = json.dumps(body).encode()
+        self.send_response(status)
+        self.send_header("Content-Type", "application/json")
+        self.send_header("Content-Length", str(len(data)))
+        self.end_headers()
+        self.wfile.write(data)
+
+    def do_GET(self):
+        url = urllib.parse.urlparse(self.path)
+        query = dict(urllib.parse.parse_qsl(url.query))
+        if url.path == "/.well-known/openid-configuration":
+            self.send_json({
+                "issuer": ISSUER,
+                "authorization_endpoint": ISSUER + "/authorize",
+                "token_endpoint": ISSUER + "/token",
+                "jwks_uri": ISSUER + "/jwks",
+            })
+        elif url.path == "/jwks":
+            numbers = key.public_key().public_numbers()
+            self.send_json({"keys": [{
+                "kty": "RSA", "kid": "mock", "alg": "RS256", "use": "sig",
+                "n": uint(numbers.n), "e": uint(numbers.e),
+            }]})
+        elif url.path == "/authorize":
+            # Logs in `query["login_hint"]` right away, without a login page
+            code = base64.urlsafe_b64encode(hashlib.sha256(str(time.time()).encode()).digest()).decode()
+            codes[code] = query
+            callback = query["redirect_uri"] + "?" + urllib.parse.urlencode(
+                {"code": code, "state": query["state"]})
+            self.send_response(302)
+            self.send_header("Location", callback)
+            self.end_headers()
+        else:
+            self.send_json({"error": "not_found"}, 404)
+
+    def do_POST(self):
+        length = int(self.headers["Content-Length"])
+        form = dict(urllib.parse.parse_qsl(self.rfile.read(length).decode()))
+        login = codes.pop(form.get("code"), None)
+        if login is None:
+            return self.send_json({"error": "invalid_grant"}, 400)
+        challenge = b64(hashlib.sha256(form["code_verifier"].encode()).digest())
+        if challenge != login["code_challenge"]:
+            return self.send_json({"error": "invalid_grant"}, 400)
+        now = int(time.time())
+        self.send_json({"access_token": "mock", "token_type": "Bearer", "id_token": sign({
+            "iss": ISSUER, "aud": login["client_id"], "sub": "mock-" + login["login_hint"],
+            "preferred_username": login["login_hint"], "nonce": login["nonce"],
+            "iat": now, "exp": now + 300,
+        })})
+
+    def log_message(self, *args):
+        pass
+
+
+class NoRedirect(urllib.request.HTTPRedirectHandler):
+    def redirect_request(self, *args):
+        return None
+
+
+opener = urllib.request.build_opener(NoRedirect)
+
+
+def get(url, cookie=None):
+    request = urllib.request.Request(url)
+    if cookie:
+        request.add_header("Cookie", cookie)
+    try:
+        with opener.open(request) as response:
+            return response.status, response.headers, response.read()
+    except urllib.error.HTTPError as e:
+        return e.code, e.headers, e.read()
+
+
+def start_login(username):
+    """Login page up to the redirect back to us: (callback URL, state cookie)"""
+    status, headers, _ = get(LOGIN)
+    assert status in (302, 303, 307), f"login returned {status}"
+    cookie = headers["Set-Cookie"]
+    assert cookie and "HttpOnly" in cookie and "SameSite=Lax" in cookie, cookie
+    authorize = headers["Location"] + "&" + urllib.parse.urlencode({"login_hint": username})
+    status, headers, _ = get(authorize)
+    assert status == 302, f"provider returned {status}"
+    return headers["Location"], cookie.split(";")[0]
+
+
+def check(name, ok):
+    print(("ok    " if ok else "FAIL  ") + name)
+    return ok
+
+
+def main():
+    threading.Thread(target=HTTPServer(("127.0.0.1", 9000), Provider).serve_forever,
+                     daemon=True).start()
+    results = []
+
+    callback, cookie = start_login("mockuser")
+    status, _, body = get(callback, cookie)
+    results.append(check("login in the same browser", status == 200 and b"token" in body))
+
+    # Attacker logs in at the provider and sends the victim the callback URL
+    callback, _ = start_login("attacker")
+    status, _, body = get(callback)
+    results.append(check("callback without the state cookie is rejected",
+                         status == 400 and b"INVALID_OIDC_STATE" in body))
+
+    callback, _ = start_login("attacker")
+    _, victim_cookie = start_login("mockuser")
+    status, _, body = get(callback, victim_cookie)
+    results.append(check("callback with another login's state cookie is rejected",
+                         status == 400 and b"INVALID_OIDC_STATE" in body))
+
+    sys.exit(0 if all(results) else 1)
+
+
+if __name__ == "__main__":
+    main()
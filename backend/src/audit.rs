@@ -0,0 +1,126 @@
+use std::net::IpAddr;
+use std::path::PathBuf;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::RwLock;
+
+use crate::models::{AuditAction, AuditEntry, AuditLogQuery};
+
+const DEFAULT_QUERY_LIMIT: usize = 100;
+const MAX_QUERY_LIMIT: usize = 1000;
+
+// Append-only log of administrative and destructive actions.
+// Every entry is written to disk right away as one JSON line, not with the periodic save.
+pub struct AuditLog {
+    entries: RwLock<Vec<AuditEntry>>,
+    path: PathBuf,
+}
+
+impl AuditLog {
+    pub async fn load(path: PathBuf) -> Self {
+        let entries = match tokio::fs::read_to_string(&path).await {
+            Ok(data) => data
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .filter_map(|line| match serde_json::from_str(line) {
+                    Ok(entry) => Some(entry),
+                    Err(e) => {
+                        eprintln!("Skipping invalid audit log entry: {e}");
+                        None
+                    }
+                })
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+
+        Self {
+            entries: RwLock::new(entries),
+            path,
+        }
+    }
+
+    pub async fn record(
+        &self,
+        actor: &str,
+        ip: IpAddr,
+        action: AuditAction,
+        target: String,
+        before: Option<String>,
+        after: Option<String>,
+    ) {
+        let created_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        // Held while writing, so lines end up in the file in id order
+        let mut entries = self.entries.write().await;
+        let entry = AuditEntry {
+            id: entries.last().map(|e| e.id).unwrap_or(0) + 1,
+            actor: actor.to_string(),
+            action,
+            target,
+            before,
+            after,
+            ip: ip.to_string(),
+            created_at: created_at.to_string(),
+        };
+
+        // The action already happened, so a failed write is only reported
+        if let Err(e) = self.append(&entry).await {
+            eprintln!("Error writing audit log: {e}");
+        }
+        entries.push(entry);
+    }
+
+    async fn append(&self, entry: &AuditEntry) -> std::io::Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(line.as_bytes()).await?;
+        file.flush().await
+    }
+
+    // Every entry the user is the actor of, oldest first, for the account export
+    pub async fn entries_by(&self, actor: &str) -> Vec<AuditEntry> {
+        let entries = self.entries.read().await;
+        entries
+            .iter()
+            .filter(|e| e.actor == actor)
+            .cloned()
+            .collect()
+    }
+
+    // Matching entries, newest first
+    pub async fn query(&self, query: &AuditLogQuery) -> Vec<AuditEntry> {
+        let limit = query
+            .limit
+            .unwrap_or(DEFAULT_QUERY_LIMIT)
+            .min(MAX_QUERY_LIMIT);
+
+        let entries = self.entries.read().await;
+        entries
+            .iter()
+            .rev()
+            .filter(|e| query.actor.as_ref().is_none_or(|actor| &e.actor == actor))
+            .filter(|e| query.action.is_none_or(|action| e.action == action))
+            .filter(|e| {
+                query.target.as_ref().is_none_or(|target| {
+                    e.target == *target
+                        || (!target.contains(':')
+                            && e.target.split(':').next() == Some(target.as_str()))
+                })
+            })
+            .filter(|e| {
+                let created_at = e.created_at.parse::<u64>().unwrap_or(0);
+                query.since.is_none_or(|since| created_at >= since)
+                    && query.until.is_none_or(|until| created_at < until)
+            })
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+}
@@ -1,6 +1,16 @@
+use axum::{
+    extract::{FromRequestParts, OptionalFromRequestParts},
+    http::{header, request::Parts},
+};
 use rand::Rng;
 use sha2::{Digest, Sha256};
 use std::collections::HashMap;
+use std::convert::Infallible;
+use std::marker::PhantomData;
+
+use crate::error::ApiError;
+use crate::models::{Permission, Role, TokenScope};
+use crate::state::AppState;
 
 const TOKEN_PREFIX: &str = "Bearer ";
 
@@ -21,6 +31,27 @@ pub fn verify_password(password: &str, salt: &str, hash: &str) -> bool {
     hash_password(password, salt) == hash
 }
 
+// Short code without ambiguous characters, meant to be handed over in person
+pub fn generate_short_code() -> String {
+    const CHARSET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+    let mut rng = rand::thread_rng();
+    (0..8)
+        .map(|_| CHARSET[rng.gen_range(0..CHARSET.len())] as char)
+        .collect()
+}
+
+pub const API_TOKEN_PREFIX: &str = "tb_";
+
+// API tokens are prefixed, so they can be told apart from session tokens
+pub fn generate_api_token() -> String {
+    format!("{}{}", API_TOKEN_PREFIX, generate_token())
+}
+
+// Tokens are random enough that an unsalted hash is fine
+pub fn hash_api_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
 pub fn generate_token() -> String {
     let mut rng = rand::thread_rng();
     let token_bytes: [u8; 32] = rng.r#gen();
@@ -57,4 +88,123 @@ impl SessionManager {
     pub fn remove_session(&mut self, token: &str) -> Option<String> {
         self.sessions.remove(token)
     }
+
+    pub fn remove_user_sessions(&mut self, username: &str) {
+        self.sessions.retain(|_, u| u != username);
+    }
+
+    pub fn remove_other_sessions(&mut self, username: &str, keep_token: &str) {
+        self.sessions
+            .retain(|token, u| u != username || token == keep_token);
+    }
+}
+
+// Authenticated user, extracted from the bearer token
+pub struct AuthUser {
+    pub username: String,
+    pub token: String,
+    pub role: Role,
+    // Set when authenticated with an API token instead of a session
+    pub scope: Option<TokenScope>,
+}
+
+impl AuthUser {
+    pub fn can(&self, permission: Permission) -> bool {
+        self.role.can(permission) && self.scope.is_none_or(|s| s.permits(permission))
+    }
+}
+
+impl FromRequestParts<AppState> for AuthUser {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let auth_header = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|h| h.to_str().ok());
+        let token = extract_token(auth_header).ok_or(ApiError::NotAuthenticated)?;
+
+        let (username, scope) = state
+            .resolve_token(&token)
+            .await
+            .ok_or(ApiError::InvalidToken)?;
+
+        if let Some(scope) = scope
+            && !scope.allows(parts.method.as_str(), parts.uri.path())
+        {
+            return Err(ApiError::forbidden(
+                "TOKEN_SCOPE",
+                "This API token can't be used for this request",
+            ));
+        }
+
+        let role = state.role_of(&username).await;
+
+        Ok(Self {
+            username,
+            token,
+            role,
+            scope,
+        })
+    }
+}
+
+// Public endpoints take `Option<AuthUser>`, a missing or invalid token is anonymous
+impl OptionalFromRequestParts<AppState> for AuthUser {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Option<Self>, Self::Rejection> {
+        Ok(
+            <Self as FromRequestParts<AppState>>::from_request_parts(parts, state)
+                .await
+                .ok(),
+        )
+    }
+}
+
+// Permission an endpoint requires as a whole, used with `Require`
+pub trait RequiredPermission {
+    const PERMISSION: Permission;
+}
+
+pub struct ModerateContent;
+
+impl RequiredPermission for ModerateContent {
+    const PERMISSION: Permission = Permission::ModerateContent;
+}
+
+pub struct ManageUsers;
+
+impl RequiredPermission for ManageUsers {
+    const PERMISSION: Permission = Permission::ManageUsers;
+}
+
+// Authenticated user that has permission `P`, rejects with 403 otherwise
+pub struct Require<P>(pub AuthUser, pub PhantomData<P>);
+
+impl<P: RequiredPermission> FromRequestParts<AppState> for Require<P> {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let user =
+            <AuthUser as FromRequestParts<AppState>>::from_request_parts(parts, state).await?;
+
+        if !user.can(P::PERMISSION) {
+            return Err(ApiError::forbidden(
+                "FORBIDDEN",
+                "You don't have permission to do this",
+            ));
+        }
+
+        Ok(Self(user, PhantomData))
+    }
 }
@@ -0,0 +1,187 @@
+use axum::{
+    extract::{ConnectInfo, FromRequestParts, Request, State},
+    http::{HeaderMap, header, request::Parts},
+    middleware::Next,
+    response::Response,
+};
+use std::convert::Infallible;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+
+use crate::models::ForwardedHeader;
+use crate::state::AppState;
+
+// Address range in CIDR notation, a plain address is a range of one
+#[derive(Debug, Clone, Copy)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid address in {s}"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .trim()
+                .parse()
+                .ok()
+                .filter(|&p| p <= max)
+                .ok_or_else(|| format!("invalid prefix length in {s}"))?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl IpNet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of a dual stack socket show up as IPv4-mapped IPv6 addresses
+        match (self.addr.to_canonical(), ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// Parses `trusted_proxies` from the settings, skipping invalid entries
+pub fn parse_trusted_proxies(entries: &[String]) -> Vec<IpNet> {
+    entries
+        .iter()
+        .filter_map(|entry| match entry.parse() {
+            Ok(net) => Some(net),
+            Err(e) => {
+                eprintln!("Ignoring trusted proxy: {e}");
+                None
+            }
+        })
+        .collect()
+}
+
+// Address of a `for=` node or X-Forwarded-For entry, which may be quoted or carry a port
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    node.parse()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|a| a.ip()))
+        .or_else(|| {
+            let inner = node.strip_prefix('[')?.split(']').next()?;
+            inner.parse().ok()
+        })
+}
+
+// Hops from the client towards us, None for hops that are obfuscated or unparsable.
+// Only the configured header is read, a client can send the other one through the proxy.
+fn forwarded_chain(headers: &HeaderMap, source: ForwardedHeader) -> Vec<Option<IpAddr>> {
+    match source {
+        ForwardedHeader::Forwarded => headers
+            .get_all(header::FORWARDED)
+            .iter()
+            .filter_map(|h| h.to_str().ok())
+            .flat_map(|h| h.split(','))
+            .map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (key, value) = pair.split_once('=')?;
+                    key.trim()
+                        .eq_ignore_ascii_case("for")
+                        .then(|| parse_node(value))
+                })?
+            })
+            .collect(),
+        ForwardedHeader::XForwardedFor => headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|h| h.to_str().ok())
+            .flat_map(|h| h.split(','))
+            .map(parse_node)
+            .collect(),
+    }
+}
+
+// Walks the forwarding chain back from the peer while hops are trusted proxies
+pub fn client_ip(
+    peer: IpAddr,
+    headers: &HeaderMap,
+    source: ForwardedHeader,
+    trusted: &[IpNet],
+) -> IpAddr {
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|net| net.contains(ip));
+
+    let mut client = peer;
+    if !is_trusted(client) {
+        return client.to_canonical();
+    }
+
+    for hop in forwarded_chain(headers, source).into_iter().rev() {
+        let Some(ip) = hop else {
+            break;
+        };
+        client = ip;
+        if !is_trusted(client) {
+            break;
+        }
+    }
+
+    client.to_canonical()
+}
+
+// Real address of the client, set by `resolve_client_ip`
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+impl ClientIp {
+    fn from_connection(parts: &Parts) -> IpAddr {
+        parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip())
+            .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts
+            .extensions
+            .get::<ClientIp>()
+            .copied()
+            .unwrap_or_else(|| ClientIp(Self::from_connection(parts))))
+    }
+}
+
+// Middleware storing the `ClientIp` of every request, must wrap everything that logs or limits
+pub async fn resolve_client_ip(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let (mut parts, body) = request.into_parts();
+    let peer = ClientIp::from_connection(&parts);
+    let ip = client_ip(
+        peer,
+        &parts.headers,
+        state.settings.forwarded_header,
+        &state.trusted_proxies,
+    );
+    parts.extensions.insert(ClientIp(ip));
+    next.run(Request::from_parts(parts, body)).await
+}
@@ -0,0 +1,157 @@
+use axum::{
+    Json,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+
+use crate::models::ErrorResponse;
+use crate::rate_limit::RateLimitError;
+
+// Error returned by handlers, rendered as `ErrorResponse` with a stable code
+#[derive(Debug)]
+pub enum ApiError {
+    NotAuthenticated,
+    InvalidToken,
+    InvalidCredentials {
+        timeout: u64,
+    },
+    RateLimited(RateLimitError),
+    NotFound(&'static str),
+    // `If-Match` didn't match the current version
+    PreconditionFailed,
+    BadRequest {
+        code: &'static str,
+        message: String,
+    },
+    Forbidden {
+        code: &'static str,
+        message: String,
+    },
+    Conflict {
+        code: &'static str,
+        message: String,
+    },
+    Internal {
+        code: &'static str,
+        message: String,
+    },
+    #[cfg_attr(not(feature = "oidc"), allow(dead_code))]
+    BadGateway {
+        code: &'static str,
+        message: String,
+    },
+}
+
+impl ApiError {
+    pub fn bad_request(code: &'static str, message: impl Into<String>) -> Self {
+        Self::BadRequest {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn forbidden(code: &'static str, message: impl Into<String>) -> Self {
+        Self::Forbidden {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn conflict(code: &'static str, message: impl Into<String>) -> Self {
+        Self::Conflict {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn internal(code: &'static str, message: impl Into<String>) -> Self {
+        Self::Internal {
+            code,
+            message: message.into(),
+        }
+    }
+
+    #[cfg_attr(not(feature = "oidc"), allow(dead_code))]
+    pub fn bad_gateway(code: &'static str, message: impl Into<String>) -> Self {
+        Self::BadGateway {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::NotAuthenticated
+            | ApiError::InvalidToken
+            | ApiError::InvalidCredentials { .. } => StatusCode::UNAUTHORIZED,
+            ApiError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            ApiError::BadRequest { .. } => StatusCode::BAD_REQUEST,
+            ApiError::Forbidden { .. } => StatusCode::FORBIDDEN,
+            ApiError::Conflict { .. } => StatusCode::CONFLICT,
+            ApiError::Internal { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::BadGateway { .. } => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    pub fn code(&self) -> &str {
+        match self {
+            ApiError::NotAuthenticated => "NOT_AUTHENTICATED",
+            ApiError::InvalidToken => "INVALID_TOKEN",
+            ApiError::InvalidCredentials { .. } => "INVALID_CREDENTIALS",
+            ApiError::RateLimited(e) => e.code(),
+            ApiError::NotFound(_) => "NOT_FOUND",
+            ApiError::PreconditionFailed => "PRECONDITION_FAILED",
+            ApiError::BadRequest { code, .. }
+            | ApiError::Forbidden { code, .. }
+            | ApiError::Conflict { code, .. }
+            | ApiError::Internal { code, .. }
+            | ApiError::BadGateway { code, .. } => code,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            ApiError::NotAuthenticated => "Not authenticated",
+            ApiError::InvalidToken => "Invalid token",
+            ApiError::InvalidCredentials { .. } => "Invalid credentials",
+            ApiError::RateLimited(e) => e.message(),
+            ApiError::NotFound(message) => message,
+            ApiError::PreconditionFailed => "Resource was changed in the meantime",
+            ApiError::BadRequest { message, .. }
+            | ApiError::Forbidden { message, .. }
+            | ApiError::Conflict { message, .. }
+            | ApiError::Internal { message, .. }
+            | ApiError::BadGateway { message, .. } => message,
+        }
+    }
+
+    pub fn timeout(&self) -> Option<u64> {
+        match self {
+            ApiError::InvalidCredentials { timeout } => Some(*timeout),
+            ApiError::RateLimited(e) => Some(e.timeout()),
+            _ => None,
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (
+            self.status(),
+            Json(ErrorResponse {
+                error: self.message().to_string(),
+                code: self.code().to_string(),
+                timeout: self.timeout(),
+            }),
+        )
+            .into_response()
+    }
+}
+
+impl From<RateLimitError> for ApiError {
+    fn from(error: RateLimitError) -> Self {
+        ApiError::RateLimited(error)
+    }
+}
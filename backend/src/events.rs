@@ -0,0 +1,87 @@
+use tokio::sync::broadcast;
+
+use crate::models::{Grade, GradeSubmittedEvent, Problem, ProblemDetail};
+
+// Events a slow subscriber may fall behind before it is told to resync
+const EVENT_BUFFER: usize = 256;
+
+// Change of a problem, broadcast to `/events` subscribers
+#[derive(Debug, Clone)]
+pub struct ProblemEvent {
+    pub kind: ProblemEventKind,
+    pub problem_id: u32,
+    pub sector_id: u16,
+    // To check visibility per subscriber, like `Problem::is_visible_to`
+    pub author: String,
+    pub hidden: bool,
+}
+
+#[derive(Debug, Clone)]
+pub enum ProblemEventKind {
+    Created(ProblemDetail),
+    Updated(ProblemDetail),
+    Deleted,
+    GradeSubmitted(GradeSubmittedEvent),
+}
+
+impl ProblemEvent {
+    fn new(problem: &Problem, kind: ProblemEventKind) -> Self {
+        Self {
+            kind,
+            problem_id: problem.id,
+            sector_id: problem.sector_id,
+            author: problem.author.clone(),
+            hidden: problem.hidden,
+        }
+    }
+
+    pub fn created(problem: &Problem) -> Self {
+        Self::new(problem, ProblemEventKind::Created(problem.to_detail()))
+    }
+
+    pub fn updated(problem: &Problem) -> Self {
+        Self::new(problem, ProblemEventKind::Updated(problem.to_detail()))
+    }
+
+    pub fn deleted(problem: &Problem) -> Self {
+        Self::new(problem, ProblemEventKind::Deleted)
+    }
+
+    pub fn grade_submitted(problem: &Problem, grade: Grade) -> Self {
+        let (average_grade, average_stars) = problem.averages();
+        let event = GradeSubmittedEvent {
+            problem_id: problem.id,
+            sector_id: problem.sector_id,
+            grade,
+            average_grade,
+            average_stars,
+        };
+        Self::new(problem, ProblemEventKind::GradeSubmitted(event))
+    }
+
+    pub fn is_visible_to(&self, username: Option<&str>, can_see_hidden: bool) -> bool {
+        !self.hidden || can_see_hidden || username.is_some_and(|u| u == self.author)
+    }
+}
+
+// Publish while holding the problems write lock, so events arrive in the order of the changes
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<ProblemEvent>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUFFER);
+        Self { sender }
+    }
+
+    pub fn publish(&self, event: ProblemEvent) {
+        // Fails only when nobody is listening
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ProblemEvent> {
+        self.sender.subscribe()
+    }
+}
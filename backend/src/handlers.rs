@@ -1,109 +1,221 @@
+#[cfg(feature = "oidc")]
+use axum::response::Redirect;
 use axum::{
     Json,
-    extract::{ConnectInfo, Path, Query, State},
+    extract::{Path, Query, State},
     http::{HeaderMap, StatusCode, header},
-    response::{IntoResponse, Response},
+    response::{
+        IntoResponse, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
 };
-use serde::Deserialize;
-use std::net::SocketAddr;
-
-use crate::auth::{extract_token, generate_salt, hash_password, verify_password};
+use futures_util::{Stream, stream};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::net::IpAddr;
+#[cfg(feature = "oidc")]
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::auth::{
+    AuthUser, ManageUsers, ModerateContent, Require, extract_token, generate_api_token,
+    generate_salt, generate_short_code, hash_api_token, hash_password, verify_password,
+};
+use crate::client_ip::ClientIp;
+use crate::error::ApiError;
+use crate::events::{ProblemEvent, ProblemEventKind};
 use crate::models::*;
+#[cfg(feature = "oidc")]
+use crate::oidc::{OidcClient, OidcError};
+use crate::problem_store::{ProblemMut, ProblemStore};
 
 use crate::state::AppState;
 
 // Helper to get current timestamp
 fn now() -> String {
+    now_secs().to_string()
+}
+
+fn now_secs() -> u64 {
     std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
         .unwrap()
         .as_secs()
-        .to_string()
 }
 
-// Helper to get authenticated user
-async fn get_auth_user(
-    state: &AppState,
-    headers: &HeaderMap,
-) -> Result<(String, String), Response> {
-    let auth_header = headers
-        .get(header::AUTHORIZATION)
-        .and_then(|h| h.to_str().ok());
-    let token = extract_token(auth_header).ok_or_else(|| {
-        (
-            StatusCode::UNAUTHORIZED,
-            Json(ErrorResponse {
-                error: "Not authenticated".to_string(),
-                code: "NOT_AUTHENTICATED".to_string(),
-                timeout: None,
-            }),
-        )
-            .into_response()
-    })?;
+// Name of an enum value as used in the API, for audit log summaries
+fn api_name<T: Serialize>(value: &T) -> String {
+    serde_json::to_value(value)
+        .ok()
+        .and_then(|v| v.as_str().map(str::to_string))
+        .unwrap_or_default()
+}
 
-    let sessions = state.sessions.read().await;
-    let username = sessions.get_username(&token).ok_or_else(|| {
-        (
-            StatusCode::UNAUTHORIZED,
-            Json(ErrorResponse {
-                error: "Invalid token".to_string(),
-                code: "INVALID_TOKEN".to_string(),
-                timeout: None,
-            }),
-        )
-            .into_response()
-    })?;
+// Fails unless `If-Match` is missing or lists an ETag of the current edit version, weak tags
+// never match. ETags are `"{version}-{hash}"`, only the version counts here.
+fn check_if_match(headers: &HeaderMap, version: u32) -> Result<(), ApiError> {
+    let mut values = headers.get_all(header::IF_MATCH).iter().peekable();
+    if values.peek().is_none() {
+        return Ok(());
+    }
+
+    let matches = values
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .any(|tag| {
+            tag == "*"
+                || tag
+                    .strip_prefix('"')
+                    .and_then(|tag| tag.strip_suffix('"'))
+                    .and_then(|tag| tag.split('-').next())
+                    .is_some_and(|v| v == version.to_string())
+        });
+    if matches {
+        Ok(())
+    } else {
+        Err(ApiError::PreconditionFailed)
+    }
+}
+
+// Whether `If-None-Match` lists the current ETag, so the client's copy is still fresh
+pub fn if_none_match(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
 
-    Ok((username.clone(), token))
+// Favorites/projects of the caller
+struct ProblemMarks {
+    favorites: HashSet<u32>,
+    projects: HashSet<u32>,
+}
+
+impl ProblemMarks {
+    async fn load(state: &AppState, username: Option<&str>) -> Self {
+        let users = state.users.read().await;
+        let caller = username.and_then(|name| users.iter().find(|u| u.username == name));
+
+        Self {
+            favorites: caller
+                .map(|u| u.favorites.iter().copied().collect())
+                .unwrap_or_default(),
+            projects: caller
+                .map(|u| u.projects.iter().copied().collect())
+                .unwrap_or_default(),
+        }
+    }
+
+    fn summary(&self, problem: &Problem) -> ProblemSummary {
+        let mut summary = problem.to_summary();
+        summary.is_favorite = self.favorites.contains(&problem.id);
+        summary.is_project = self.projects.contains(&problem.id);
+        summary
+    }
+
+    fn detail(&self, problem: &Problem) -> ProblemDetail {
+        let mut detail = problem.to_detail();
+        detail.is_favorite = self.favorites.contains(&problem.id);
+        detail.is_project = self.projects.contains(&problem.id);
+        detail
+    }
 }
 
 // Auth handlers
+fn validate_password(password: &str) -> Result<(), ApiError> {
+    if password.len() < 6 {
+        return Err(ApiError::bad_request(
+            "INVALID_PASSWORD",
+            "Password must be at least 6 characters",
+        ));
+    }
+    Ok(())
+}
+
+// Replaces the password with a fresh salt, invalidating any pending reset code
+fn set_password(user: &mut User, password: &str) {
+    user.salt = generate_salt();
+    user.password_hash = hash_password(password, &user.salt);
+    user.password_reset = None;
+}
+
 pub async fn register(
     State(state): State<AppState>,
     Json(payload): Json<RegisterRequest>,
-) -> Result<impl IntoResponse, (StatusCode, Json<ErrorResponse>)> {
+) -> Result<impl IntoResponse, ApiError> {
     if payload.username.len() < 3 || payload.username.len() > 50 {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            Json(ErrorResponse {
-                error: "Username must be between 3 and 50 characters".to_string(),
-                code: "INVALID_USERNAME".to_string(),
-                timeout: None,
-            }),
+        return Err(ApiError::bad_request(
+            "INVALID_USERNAME",
+            "Username must be between 3 and 50 characters",
         ));
     }
 
-    if payload.password.len() < 6 {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            Json(ErrorResponse {
-                error: "Password must be at least 6 characters".to_string(),
-                code: "INVALID_PASSWORD".to_string(),
-                timeout: None,
-            }),
+    if payload.username == DELETED_USERNAME {
+        return Err(ApiError::bad_request(
+            "INVALID_USERNAME",
+            "This username is reserved",
         ));
     }
 
+    validate_password(&payload.password)?;
+
     let mut users = state.users.write().await;
 
+    // The first account may be a configured admin whatever the mode, so a closed board can
+    // still be set up. Later on an admin name is just a name anyone could take.
+    let mode = if users.is_empty() && state.is_admin(&payload.username) {
+        RegistrationMode::Open
+    } else {
+        state.settings.registration_mode
+    };
+
+    if mode == RegistrationMode::Closed {
+        return Err(ApiError::forbidden(
+            "REGISTRATION_CLOSED",
+            "Registration is closed",
+        ));
+    }
+
     if users.iter().any(|u| u.username == payload.username) {
-        return Err((
-            StatusCode::CONFLICT,
-            Json(ErrorResponse {
-                error: "Username already exists".to_string(),
-                code: "USERNAME_EXISTS".to_string(),
-                timeout: None,
-            }),
+        return Err(ApiError::conflict(
+            "USERNAME_EXISTS",
+            "Username already exists",
         ));
     }
 
+    if mode == RegistrationMode::InviteCode {
+        let now = now_secs();
+        let mut invites = state.invites.write().await;
+        let invite = invites
+            .iter_mut()
+            .find(|i| Some(&i.code) == payload.invite_code.as_ref() && i.is_valid(now))
+            .ok_or_else(|| {
+                ApiError::forbidden("INVALID_INVITE_CODE", "A valid invite code is required")
+            })?;
+        invite.uses += 1;
+    }
+
     let salt = generate_salt();
     let password_hash = hash_password(&payload.password, &salt);
+    let pending_approval = mode == RegistrationMode::AdminApproval;
 
     let user = User {
         username: payload.username.clone(),
         password_hash,
         salt,
+        favorites: Vec::new(),
+        projects: Vec::new(),
+        banned: false,
+        pending_approval,
+        role: Role::Member,
+        password_reset: None,
+        oidc_subject: None,
     };
 
     users.push(user);
@@ -111,38 +223,37 @@ pub async fn register(
 
     state.mark_dirty();
 
-    let is_admin = state.is_admin(&payload.username);
+    if pending_approval {
+        return Ok((
+            StatusCode::ACCEPTED,
+            Json(PendingRegistration {
+                username: payload.username,
+                pending_approval,
+            }),
+        )
+            .into_response());
+    }
+
+    let role = state.role_of(&payload.username).await;
 
     let mut sessions = state.sessions.write().await;
     let token = sessions.create_session(payload.username.clone());
 
     Ok((
         StatusCode::CREATED,
-        Json(LoginResponse {
-            token,
-            username: payload.username,
-            is_admin,
-        }),
-    ))
+        Json(LoginResponse::new(token, payload.username, role)),
+    )
+        .into_response())
 }
 
 pub async fn login(
     State(state): State<AppState>,
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    ClientIp(ip): ClientIp,
     Json(payload): Json<LoginRequest>,
-) -> Result<impl IntoResponse, (StatusCode, Json<ErrorResponse>)> {
-    let ip = addr.ip();
-
+) -> Result<impl IntoResponse, ApiError> {
     let mut rate_limiter = state.rate_limiter.write().await;
-    if let Err(rate_error) = rate_limiter.check_and_wait(ip) {
-        return Err((
-            StatusCode::TOO_MANY_REQUESTS,
-            Json(ErrorResponse {
-                error: rate_error.message().to_string(),
-                code: rate_error.code().to_string(),
-                timeout: Some(rate_error.timeout()),
-            }),
-        ));
+    if let Err(rate_error) = rate_limiter.check_and_wait(ip, &payload.username) {
+        return Err(rate_error.into());
     }
     drop(rate_limiter);
 
@@ -155,68 +266,61 @@ pub async fn login(
         None => {
             drop(users);
             let mut rate_limiter = state.rate_limiter.write().await;
-            let timeout = rate_limiter.record_failed_attempt(ip);
-            return Err((
-                StatusCode::UNAUTHORIZED,
-                Json(ErrorResponse {
-                    error: "Invalid credentials".to_string(),
-                    code: "INVALID_CREDENTIALS".to_string(),
-                    timeout: Some(timeout),
-                }),
-            ));
+            let timeout = rate_limiter.record_failed_attempt(ip, &payload.username);
+            drop(rate_limiter);
+            state.mark_dirty();
+            return Err(ApiError::InvalidCredentials { timeout });
         }
     };
 
     if !verify_password(&payload.password, &user.salt, &user.password_hash) {
         drop(users);
         let mut rate_limiter = state.rate_limiter.write().await;
-        let timeout = rate_limiter.record_failed_attempt(ip);
-        return Err((
-            StatusCode::UNAUTHORIZED,
-            Json(ErrorResponse {
-                error: "Invalid credentials".to_string(),
-                code: "INVALID_CREDENTIALS".to_string(),
-                timeout: Some(timeout),
-            }),
+        let timeout = rate_limiter.record_failed_attempt(ip, &payload.username);
+        drop(rate_limiter);
+        state.mark_dirty();
+        return Err(ApiError::InvalidCredentials { timeout });
+    }
+
+    if user.banned {
+        return Err(ApiError::forbidden(
+            "ACCOUNT_BANNED",
+            "This account has been banned",
+        ));
+    }
+
+    if user.pending_approval {
+        return Err(ApiError::forbidden(
+            "PENDING_APPROVAL",
+            "This account is waiting for admin approval",
         ));
     }
 
     let username = user.username.clone();
     drop(users);
 
-    let is_admin = state.is_admin(&username);
+    let role = state.role_of(&username).await;
 
     let mut rate_limiter = state.rate_limiter.write().await;
-    rate_limiter.record_successful_attempt(ip);
+    if rate_limiter.record_successful_attempt(ip, &payload.username) {
+        state.mark_dirty();
+    }
     drop(rate_limiter);
 
     let mut sessions = state.sessions.write().await;
     let token = sessions.create_session(username.clone());
 
-    Ok(Json(LoginResponse {
-        token,
-        username,
-        is_admin,
-    }))
+    Ok(Json(LoginResponse::new(token, username, role)))
 }
 
 pub async fn logout(
     State(state): State<AppState>,
     headers: HeaderMap,
-) -> Result<impl IntoResponse, (StatusCode, Json<ErrorResponse>)> {
+) -> Result<impl IntoResponse, ApiError> {
     let auth_header = headers
         .get(header::AUTHORIZATION)
         .and_then(|h| h.to_str().ok());
-    let token = extract_token(auth_header).ok_or_else(|| {
-        (
-            StatusCode::UNAUTHORIZED,
-            Json(ErrorResponse {
-                error: "Not authenticated".to_string(),
-                code: "NOT_AUTHENTICATED".to_string(),
-                timeout: None,
-            }),
-        )
-    })?;
+    let token = extract_token(auth_header).ok_or(ApiError::NotAuthenticated)?;
 
     let mut sessions = state.sessions.write().await;
     sessions.remove_session(&token);
@@ -224,450 +328,2872 @@ pub async fn logout(
     Ok(StatusCode::NO_CONTENT)
 }
 
-pub async fn rotate_token(
-    State(state): State<AppState>,
-    headers: HeaderMap,
-) -> Result<impl IntoResponse, Response> {
-    let (username, token) = get_auth_user(&state, &headers).await?;
-
-    let is_admin = state.is_admin(&username);
+pub async fn rotate_token(State(state): State<AppState>, user: AuthUser) -> Json<LoginResponse> {
     let mut sessions = state.sessions.write().await;
-    sessions.remove_session(&token);
-    let token = sessions.create_session(username.clone());
+    sessions.remove_session(&user.token);
+    let token = sessions.create_session(user.username.clone());
 
-    Ok(Json(LoginResponse {
-        token,
-        username,
-        is_admin,
-    }))
+    Json(LoginResponse::new(token, user.username, user.role))
 }
 
-// Sector handlers
-pub async fn list_sectors(
+pub async fn change_password(
     State(state): State<AppState>,
-) -> Result<Json<Vec<SectorSummary>>, (StatusCode, Json<ErrorResponse>)> {
-    Ok(Json(state.sectors.clone()))
-}
+    user: AuthUser,
+    Json(payload): Json<ChangePasswordRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    validate_password(&payload.new_password)?;
 
-pub async fn get_sector(
-    State(state): State<AppState>,
-    Path(id): Path<u16>,
-) -> Result<Json<Sector>, (StatusCode, Json<ErrorResponse>)> {
-    let metadata = state.sector_metadata.get(&id).ok_or_else(|| {
-        (
-            StatusCode::NOT_FOUND,
-            Json(ErrorResponse {
-                error: "Sector not found".to_string(),
-                code: "NOT_FOUND".to_string(),
-                timeout: None,
-            }),
-        )
-    })?;
+    let mut users = state.users.write().await;
+    let stored = users
+        .iter_mut()
+        .find(|u| u.username == user.username)
+        .ok_or(ApiError::InvalidToken)?;
+
+    if !verify_password(
+        &payload.current_password,
+        &stored.salt,
+        &stored.password_hash,
+    ) {
+        return Err(ApiError::forbidden(
+            "WRONG_PASSWORD",
+            "Current password is incorrect",
+        ));
+    }
 
-    Ok(Json(Sector {
-        id: metadata.id.unwrap(),
-        name: metadata
-            .display_name
-            .as_deref()
-            .unwrap_or(&metadata.folder_name)
-            .to_string(),
-        holds: metadata.holds.clone(),
-        image_width: metadata.image_width,
-        image_height: metadata.image_height,
-    }))
+    set_password(stored, &payload.new_password);
+    drop(users);
+
+    state.mark_dirty();
+
+    // Other devices have to log in again with the new password
+    let mut sessions = state.sessions.write().await;
+    sessions.remove_other_sessions(&user.username, &user.token);
+
+    Ok(StatusCode::NO_CONTENT)
 }
 
-pub async fn get_sector_image(
+pub async fn reset_password(
     State(state): State<AppState>,
-    Path(id): Path<u16>,
-) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
-    let metadata = state.sector_metadata.get(&id).ok_or_else(|| {
-        (
-            StatusCode::NOT_FOUND,
-            Json(ErrorResponse {
-                error: "Sector not found".to_string(),
-                code: "NOT_FOUND".to_string(),
-                timeout: None,
-            }),
-        )
-    })?;
-
-    let sector_dir = state.sectors_path.join(&metadata.folder_name);
-    let image_filename = metadata.image_filename.as_ref().ok_or_else(|| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(ErrorResponse {
-                error: "Sector image filename not set".to_string(),
-                code: "INVALID_SECTOR_METADATA".to_string(),
-                timeout: None,
-            }),
-        )
-    })?;
-    let image_path = sector_dir.join(image_filename);
-    if !image_path.exists() {
-        return Err((
-            StatusCode::NOT_FOUND,
-            Json(ErrorResponse {
-                error: "Sector image not found".to_string(),
-                code: "NOT_FOUND".to_string(),
-                timeout: None,
-            }),
-        ));
+    ClientIp(ip): ClientIp,
+    Json(payload): Json<ResetPasswordRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    // Reset codes are short, so guessing is throttled like logins
+    let mut rate_limiter = state.rate_limiter.write().await;
+    if let Err(rate_error) = rate_limiter.check_and_wait(ip, &payload.username) {
+        return Err(rate_error.into());
     }
+    drop(rate_limiter);
 
-    let image_data = tokio::fs::read(&image_path).await.map_err(|_| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(ErrorResponse {
-                error: "Failed to read sector image".to_string(),
-                code: "IO_ERROR".to_string(),
-                timeout: None,
-            }),
-        )
-    })?;
+    validate_password(&payload.new_password)?;
 
-    let content_type = if image_filename.ends_with(".png") {
-        "image/png"
-    } else if image_filename.ends_with(".jpg") || image_filename.ends_with(".jpeg") {
-        "image/jpeg"
-    } else {
-        "application/octet-stream"
+    let now = now_secs();
+    let mut users = state.users.write().await;
+    let user = users.iter_mut().find(|u| {
+        u.username == payload.username
+            && u.password_reset.as_ref().is_some_and(|reset| {
+                reset.expires_at.parse::<u64>().is_ok_and(|at| at > now)
+                    && verify_password(
+                        &payload.code.trim().to_uppercase(),
+                        &u.salt,
+                        &reset.code_hash,
+                    )
+            })
+    });
+
+    let Some(user) = user else {
+        drop(users);
+        let mut rate_limiter = state.rate_limiter.write().await;
+        let timeout = rate_limiter.record_failed_attempt(ip, &payload.username);
+        drop(rate_limiter);
+        state.mark_dirty();
+        return Err(ApiError::InvalidCredentials { timeout });
     };
 
-    Ok(([(header::CONTENT_TYPE, content_type)], image_data).into_response())
-}
+    set_password(user, &payload.new_password);
+    drop(users);
 
-// Problem handlers
-#[derive(Debug, Deserialize)]
-pub struct ProblemQuery {
-    pub sector_id: Option<u16>,
-    pub min_grade: Option<u8>,
-    pub max_grade: Option<u8>,
-    pub name: Option<String>,
-    pub page: Option<u32>,
-    pub per_page: Option<u32>,
+    state.mark_dirty();
+
+    let mut rate_limiter = state.rate_limiter.write().await;
+    if rate_limiter.record_successful_attempt(ip, &payload.username) {
+        state.mark_dirty();
+    }
+    drop(rate_limiter);
+
+    let mut sessions = state.sessions.write().await;
+    sessions.remove_user_sessions(&payload.username);
+
+    Ok(StatusCode::NO_CONTENT)
 }
 
-pub async fn list_problems(
+// Account handlers
+pub async fn export_account(
     State(state): State<AppState>,
-    Query(query): Query<ProblemQuery>,
-) -> Result<Json<ProblemList>, (StatusCode, Json<ErrorResponse>)> {
-    let problems = state.problems.read().await;
+    user: AuthUser,
+) -> Result<impl IntoResponse, ApiError> {
+    let username = user.username;
 
-    let filtered: Vec<&Problem> = problems
+    let users = state.users.read().await;
+    let stored = users
         .iter()
-        .filter(|p| query.sector_id.map_or(true, |s| p.sector_id == s))
-        .filter(|p| query.min_grade.map_or(true, |g| p.grade >= g))
-        .filter(|p| query.max_grade.map_or(true, |g| p.grade <= g))
-        .filter(|p| {
-            query.name.as_ref().map_or(true, |name| {
-                p.name.to_lowercase().contains(&name.to_lowercase())
-            })
+        .find(|u| u.username == username)
+        .ok_or(ApiError::InvalidToken)?;
+    let favorites = stored.favorites.clone();
+    let projects = stored.projects.clone();
+    drop(users);
+
+    let problems = state.problems.read().await;
+    let authored = problems
+        .by_author(&username)
+        .map(|p| p.to_detail())
+        .collect();
+    let grades = problems
+        .iter()
+        .flat_map(|p| {
+            p.grades
+                .iter()
+                .filter(|g| g.username == username)
+                .map(|g| ExportedGrade {
+                    problem_id: p.id,
+                    grade: g.clone(),
+                })
+        })
+        .collect();
+    let comments = problems
+        .iter()
+        .flat_map(|p| {
+            p.comments
+                .iter()
+                .filter(|c| c.username == username)
+                .map(|c| ExportedComment {
+                    problem_id: p.id,
+                    comment: c.clone(),
+                })
         })
         .collect();
+    drop(problems);
 
-    let total = filtered.len() as u32;
-    let page = query.page.unwrap_or(1).max(1);
-    let per_page = query.per_page.unwrap_or(20).min(100).max(1);
+    let circuits = state.circuits.read().await;
+    let owned = circuits
+        .iter()
+        .filter(|c| c.owner == username)
+        .map(|c| c.to_detail())
+        .collect();
+    let circuit_progress = circuits
+        .iter()
+        .filter(|c| c.progress.contains_key(&username))
+        .map(|c| c.progress_of(&username))
+        .collect();
+    drop(circuits);
 
-    let skip = ((page - 1) * per_page) as usize;
-    let summaries: Vec<ProblemSummary> = filtered
-        .into_iter()
-        .skip(skip)
-        .take(per_page as usize)
-        .map(|p| p.to_summary())
+    let reports = state.reports.read().await;
+    let filed = reports
+        .iter()
+        .filter(|r| r.reporter == username)
+        .cloned()
         .collect();
+    drop(reports);
 
-    Ok(Json(ProblemList {
-        problems: summaries,
-        total,
-        page,
-        per_page,
-    }))
-}
+    let api_tokens = state.api_tokens.read().await;
+    let tokens = api_tokens
+        .iter()
+        .filter(|t| t.owner == username)
+        .map(|t| t.to_info())
+        .collect();
+    drop(api_tokens);
 
-pub async fn get_problem(
-    State(state): State<AppState>,
-    Path(id): Path<u32>,
-) -> Result<Json<ProblemDetail>, (StatusCode, Json<ErrorResponse>)> {
-    let problems = state.problems.read().await;
+    let audit_log = state.audit_log.entries_by(&username).await;
 
-    let problem = problems.iter().find(|p| p.id == id).ok_or_else(|| {
-        (
-            StatusCode::NOT_FOUND,
-            Json(ErrorResponse {
-                error: "Problem not found".to_string(),
-                code: "NOT_FOUND".to_string(),
-                timeout: None,
-            }),
-        )
-    })?;
+    let disposition = format!("attachment; filename=\"trainboard-{}.json\"", username);
+    let export = AccountExport {
+        exported_at: now(),
+        username,
+        role: user.role,
+        favorites,
+        projects,
+        problems: authored,
+        grades,
+        comments,
+        circuits: owned,
+        circuit_progress,
+        reports: filed,
+        api_tokens: tokens,
+        audit_log,
+    };
 
-    Ok(Json(problem.to_detail()))
+    Ok(([(header::CONTENT_DISPOSITION, disposition)], Json(export)))
 }
 
-pub async fn create_problem(
+pub async fn delete_account(
     State(state): State<AppState>,
-    headers: HeaderMap,
-    Json(payload): Json<CreateProblemRequest>,
-) -> Result<impl IntoResponse, Response> {
-    let (username, _) = get_auth_user(&state, &headers).await?;
+    AuthUser { username, .. }: AuthUser,
+    ClientIp(ip): ClientIp,
+    payload: Option<Json<DeleteAccountRequest>>,
+) -> Result<impl IntoResponse, ApiError> {
+    // Users created through OIDC have no password and may send no body at all
+    let password = payload.map(|Json(p)| p.password).unwrap_or_default();
 
-    // Validate that sector_id exists
-    let sector_exists = state
-        .sector_metadata
-        .values()
-        .any(|metadata| metadata.id == Some(payload.sector_id));
+    let mut users = state.users.write().await;
+    let pos = users
+        .iter()
+        .position(|u| u.username == username)
+        .ok_or(ApiError::InvalidToken)?;
+
+    // Users created through OIDC have no password to confirm with
+    let stored = &users[pos];
+    if !stored.password_hash.is_empty()
+        && !verify_password(&password, &stored.salt, &stored.password_hash)
+    {
+        return Err(ApiError::forbidden(
+            "WRONG_PASSWORD",
+            "Current password is incorrect",
+        ));
+    }
+    let removed = users.remove(pos);
+    drop(users);
 
-    if !sector_exists {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            Json(ErrorResponse {
-                error: "Sector does not exist".to_string(),
-                code: "INVALID_SECTOR".to_string(),
-                timeout: None,
-            }),
-        )
-            .into_response());
+    let mut sessions = state.sessions.write().await;
+    sessions.remove_user_sessions(&username);
+    drop(sessions);
+
+    let anonymize = state.settings.account_deletion == AccountDeletion::Anonymize;
+    let rename = |name: &mut String| {
+        if *name == username {
+            *name = DELETED_USERNAME.to_string();
+        }
+    };
+
+    let mut problems = state.problems.write().await;
+    let mut removed_problems = Vec::new();
+    if anonymize {
+        problems.update_all(|problem| {
+            let renamed = problem.author == username
+                || problem.grades.iter().any(|g| g.username == username)
+                || problem.comments.iter().any(|c| c.username == username);
+            rename(&mut problem.author);
+            problem
+                .grades
+                .iter_mut()
+                .for_each(|g| rename(&mut g.username));
+            problem
+                .comments
+                .iter_mut()
+                .for_each(|c| rename(&mut c.username));
+            let favorited = removed.favorites.contains(&problem.id);
+            if favorited {
+                problem.stats.favorite_count = problem.stats.favorite_count.saturating_sub(1);
+            }
+            if renamed || favorited {
+                problem.mark_changed(state.next_change_seq());
+                state.events.publish(ProblemEvent::updated(problem));
+            }
+        });
+    } else {
+        problems.retain(|p| {
+            let keep = p.author != username;
+            if !keep {
+                removed_problems.push(p.id);
+                state.events.publish(ProblemEvent::deleted(p));
+            }
+            keep
+        });
+        state.add_tombstones(&removed_problems).await;
+        problems.update_all(|problem| {
+            let counts = (problem.grades.len(), problem.comments.len());
+            problem.grades.retain(|g| g.username != username);
+            problem.comments.retain(|c| c.username != username);
+            let favorited = removed.favorites.contains(&problem.id);
+            if favorited {
+                problem.stats.favorite_count = problem.stats.favorite_count.saturating_sub(1);
+            }
+            if counts != (problem.grades.len(), problem.comments.len()) || favorited {
+                problem.mark_changed(state.next_change_seq());
+                state.events.publish(ProblemEvent::updated(problem));
+            }
+        });
     }
+    drop(problems);
 
-    if payload.hold_sequence.is_empty() {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            Json(ErrorResponse {
-                error: "Hold sequence cannot be empty".to_string(),
-                code: "INVALID_HOLD_SEQUENCE".to_string(),
-                timeout: None,
-            }),
-        )
-            .into_response());
+    if !removed_problems.is_empty() {
+        let mut users = state.users.write().await;
+        for user in users.iter_mut() {
+            user.favorites.retain(|p| !removed_problems.contains(p));
+            user.projects.retain(|p| !removed_problems.contains(p));
+        }
+        drop(users);
     }
 
-    let id = state.get_next_problem_id().await;
-    let name = payload.name.unwrap_or_else(|| format!("Problem {}", id));
+    // Private circuits are of no use to anyone else, so they always go
+    let mut circuits = state.circuits.write().await;
+    circuits.retain(|c| {
+        c.owner != username || (anonymize && c.visibility == CircuitVisibility::Public)
+    });
+    for circuit in circuits.iter_mut() {
+        rename(&mut circuit.owner);
+        circuit.progress.remove(&username);
+        for &id in &removed_problems {
+            circuit.remove_problem(id);
+        }
+    }
+    drop(circuits);
 
-    let problem = Problem {
-        id,
-        name,
-        description: payload.description,
-        author: username,
-        grade: payload.grade,
-        sector_id: payload.sector_id,
-        hold_sequence: payload.hold_sequence,
-        grades: Vec::new(),
-        updated_at: now(),
-    };
+    // Moderation records are kept, but no longer name the user
+    let mut reports = state.reports.write().await;
+    for report in reports.iter_mut() {
+        rename(&mut report.reporter);
+        rename(&mut report.author);
+    }
+    drop(reports);
 
-    let detail = problem.to_detail();
+    let mut moderation_log = state.moderation_log.write().await;
+    for entry in moderation_log.iter_mut() {
+        rename(&mut entry.moderator);
+        rename(&mut entry.author);
+    }
+    drop(moderation_log);
 
-    let mut problems = state.problems.write().await;
-    problems.push(problem);
-    drop(problems);
+    let mut invites = state.invites.write().await;
+    invites.iter_mut().for_each(|i| rename(&mut i.created_by));
+    drop(invites);
+
+    let mut api_tokens = state.api_tokens.write().await;
+    api_tokens.retain(|t| t.owner != username);
+    drop(api_tokens);
+
+    let mut idempotency_records = state.idempotency_records.write().await;
+    idempotency_records.retain(|(owner, _), _| *owner != username);
+    drop(idempotency_records);
 
     state.mark_dirty();
 
-    Ok((StatusCode::CREATED, Json(detail)).into_response())
+    // The audit log is append-only, so unlike the moderation log it keeps the username
+    state
+        .audit_log
+        .record(
+            &username,
+            ip,
+            AuditAction::AccountDelete,
+            format!("user:{username}"),
+            None,
+            Some(api_name(&state.settings.account_deletion)),
+        )
+        .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// OpenID Connect handlers
+#[cfg(feature = "oidc")]
+fn oidc_client(state: &AppState) -> Result<Arc<OidcClient>, ApiError> {
+    state
+        .oidc
+        .clone()
+        .ok_or(ApiError::NotFound("OIDC login is not configured"))
+}
+
+#[cfg(feature = "oidc")]
+pub async fn oidc_login(State(state): State<AppState>) -> Result<Response, ApiError> {
+    let client = oidc_client(&state)?;
+    let (url, oidc_state) = client.authorization_url().await?;
+    Ok((
+        [(header::SET_COOKIE, client.state_cookie(&oidc_state))],
+        Redirect::to(&url),
+    )
+        .into_response())
+}
+
+#[cfg(feature = "oidc")]
+#[derive(Debug, Deserialize)]
+pub struct OidcCallbackQuery {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+    pub error_description: Option<String>,
+}
+
+#[cfg(feature = "oidc")]
+pub async fn oidc_callback(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<OidcCallbackQuery>,
+) -> Result<Response, ApiError> {
+    let client = oidc_client(&state)?;
+    // The state is single use, so the cookie goes in any case
+    let clear_cookie = [(header::SET_COOKIE, client.clear_state_cookie())];
+
+    let response = oidc_finish_login(&state, &client, &headers, query).await;
+    Ok((clear_cookie, response).into_response())
+}
+
+#[cfg(feature = "oidc")]
+async fn oidc_finish_login(
+    state: &AppState,
+    client: &OidcClient,
+    headers: &HeaderMap,
+    query: OidcCallbackQuery,
+) -> Result<Response, ApiError> {
+    if let Some(error) = query.error {
+        return Err(OidcError::Denied(query.error_description.unwrap_or(error)).into());
+    }
+    let (Some(code), Some(oidc_state)) = (query.code, query.state) else {
+        return Err(OidcError::InvalidState.into());
+    };
+    // A callback the browser didn't start is someone else's login
+    client.check_state_cookie(headers, &oidc_state)?;
+
+    let identity = client.exchange(&code, &oidc_state).await?;
+    let settings = client.settings();
+
+    let mut users = state.users.write().await;
+    let linked = users
+        .iter()
+        .position(|u| u.oidc_subject.as_ref() == Some(&identity.subject));
+    let pos = match linked {
+        Some(pos) => pos,
+        None => match users.iter().position(|u| u.username == identity.username) {
+            Some(pos) if settings.link_existing_users && users[pos].oidc_subject.is_none() => {
+                users[pos].oidc_subject = Some(identity.subject);
+                state.mark_dirty();
+                pos
+            }
+            Some(_) => {
+                return Err(ApiError::conflict(
+                    "USERNAME_EXISTS",
+                    "A user with this username already exists",
+                ));
+            }
+            None if settings.auto_provision => {
+                let username = identity.username;
+                if username.len() < 3 || username.len() > 50 || username == DELETED_USERNAME {
+                    return Err(ApiError::bad_request(
+                        "INVALID_USERNAME",
+                        "Username from the identity provider is not allowed",
+                    ));
+                }
+
+                // The provider is the club's member login, so only admin approval still applies.
+                // Like with registration, only the first account may skip it as a configured admin.
+                let pending_approval = state.settings.registration_mode
+                    == RegistrationMode::AdminApproval
+                    && !(users.is_empty() && state.is_admin(&username));
+
+                users.push(User {
+                    username,
+                    password_hash: String::new(),
+                    salt: String::new(),
+                    favorites: Vec::new(),
+                    projects: Vec::new(),
+                    banned: false,
+                    pending_approval,
+                    role: Role::Member,
+                    password_reset: None,
+                    oidc_subject: Some(identity.subject),
+                });
+                state.mark_dirty();
+                users.len() - 1
+            }
+            None => {
+                return Err(ApiError::forbidden(
+                    "OIDC_NOT_LINKED",
+                    "No user is linked to this account",
+                ));
+            }
+        },
+    };
+
+    let user = &users[pos];
+    if user.banned {
+        return Err(ApiError::forbidden(
+            "ACCOUNT_BANNED",
+            "This account has been banned",
+        ));
+    }
+    if user.pending_approval {
+        return Err(ApiError::forbidden(
+            "PENDING_APPROVAL",
+            "This account is waiting for admin approval",
+        ));
+    }
+
+    let username = user.username.clone();
+    drop(users);
+
+    let role = state.role_of(&username).await;
+
+    let mut sessions = state.sessions.write().await;
+    let token = sessions.create_session(username.clone());
+    drop(sessions);
+
+    // Browsers and the app can't read a JSON body from a redirect, so the session goes into the fragment
+    if let Some(redirect) = &settings.post_login_redirect {
+        let fragment = url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("token", &token)
+            .append_pair("username", &username)
+            .finish();
+        return Ok(Redirect::to(&format!("{redirect}#{fragment}")).into_response());
+    }
+
+    Ok(Json(LoginResponse::new(token, username, role)).into_response())
+}
+
+// API token handlers
+pub async fn list_api_tokens(
+    State(state): State<AppState>,
+    AuthUser { username, .. }: AuthUser,
+) -> Json<Vec<ApiTokenInfo>> {
+    let api_tokens = state.api_tokens.read().await;
+    Json(
+        api_tokens
+            .iter()
+            .filter(|t| t.owner == username)
+            .map(|t| t.to_info())
+            .collect(),
+    )
+}
+
+pub async fn create_api_token(
+    State(state): State<AppState>,
+    AuthUser { username, .. }: AuthUser,
+    Json(payload): Json<CreateApiTokenRequest>,
+) -> Result<(StatusCode, Json<CreatedApiToken>), ApiError> {
+    if payload.name.trim().is_empty() || payload.name.len() > 50 {
+        return Err(ApiError::bad_request(
+            "INVALID_NAME",
+            "Token name must be between 1 and 50 characters",
+        ));
+    }
+
+    let token = generate_api_token();
+    let api_token = ApiToken {
+        id: state.get_next_api_token_id().await,
+        name: payload.name,
+        owner: username,
+        scope: payload.scope,
+        token_hash: hash_api_token(&token),
+        created_at: now(),
+        last_used_at: None,
+    };
+    let info = api_token.to_info();
+
+    let mut api_tokens = state.api_tokens.write().await;
+    api_tokens.push(api_token);
+    drop(api_tokens);
+
+    state.mark_dirty();
+
+    Ok((StatusCode::CREATED, Json(CreatedApiToken { token, info })))
+}
+
+async fn remove_api_token(
+    state: &AppState,
+    id: u32,
+    owner: Option<&str>,
+) -> Result<ApiToken, ApiError> {
+    let mut api_tokens = state.api_tokens.write().await;
+    let pos = api_tokens
+        .iter()
+        .position(|t| t.id == id && owner.is_none_or(|o| t.owner == o))
+        .ok_or(ApiError::NotFound("API token not found"))?;
+    let api_token = api_tokens.remove(pos);
+    drop(api_tokens);
+
+    state.mark_dirty();
+
+    Ok(api_token)
+}
+
+pub async fn revoke_api_token(
+    State(state): State<AppState>,
+    AuthUser { username, .. }: AuthUser,
+    Path(id): Path<u32>,
+) -> Result<StatusCode, ApiError> {
+    remove_api_token(&state, id, Some(&username)).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn list_all_api_tokens(
+    State(state): State<AppState>,
+    _: Require<ManageUsers>,
+) -> Json<Vec<ApiTokenInfo>> {
+    let api_tokens = state.api_tokens.read().await;
+    Json(api_tokens.iter().map(|t| t.to_info()).collect())
+}
+
+pub async fn revoke_any_api_token(
+    State(state): State<AppState>,
+    Require(admin, _): Require<ManageUsers>,
+    ClientIp(ip): ClientIp,
+    Path(id): Path<u32>,
+) -> Result<StatusCode, ApiError> {
+    let api_token = remove_api_token(&state, id, None).await?;
+
+    state
+        .audit_log
+        .record(
+            &admin.username,
+            ip,
+            AuditAction::ApiTokenRevoke,
+            format!("token:{id}"),
+            Some(format!(
+                "{} of {} ({})",
+                api_token.name,
+                api_token.owner,
+                api_name(&api_token.scope)
+            )),
+            None,
+        )
+        .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Sector handlers
+pub async fn list_sectors(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<SectorSummary>>, ApiError> {
+    Ok(Json(state.sectors.clone()))
+}
+
+pub async fn get_sector(
+    State(state): State<AppState>,
+    Path(id): Path<u16>,
+) -> Result<Json<Sector>, ApiError> {
+    let metadata = state
+        .sector_metadata
+        .get(&id)
+        .ok_or(ApiError::NotFound("Sector not found"))?;
+
+    Ok(Json(Sector {
+        id: metadata.id.unwrap(),
+        name: metadata
+            .display_name
+            .as_deref()
+            .unwrap_or(&metadata.folder_name)
+            .to_string(),
+        holds: metadata.holds.clone(),
+        image_width: metadata.image_width,
+        image_height: metadata.image_height,
+    }))
+}
+
+pub async fn get_sector_image(
+    State(state): State<AppState>,
+    Path(id): Path<u16>,
+) -> Result<Response, ApiError> {
+    let metadata = state
+        .sector_metadata
+        .get(&id)
+        .ok_or(ApiError::NotFound("Sector not found"))?;
+
+    let sector_dir = state.sectors_path.join(&metadata.folder_name);
+    let image_filename = metadata.image_filename.as_ref().ok_or_else(|| {
+        ApiError::internal("INVALID_SECTOR_METADATA", "Sector image filename not set")
+    })?;
+    let image_path = sector_dir.join(image_filename);
+    if !image_path.exists() {
+        return Err(ApiError::NotFound("Sector image not found"));
+    }
+
+    let image_data = tokio::fs::read(&image_path)
+        .await
+        .map_err(|_| ApiError::internal("IO_ERROR", "Failed to read sector image"))?;
+
+    let content_type = if image_filename.ends_with(".png") {
+        "image/png"
+    } else if image_filename.ends_with(".jpg") || image_filename.ends_with(".jpeg") {
+        "image/jpeg"
+    } else {
+        "application/octet-stream"
+    };
+
+    Ok(([(header::CONTENT_TYPE, content_type)], image_data).into_response())
+}
+
+// Problem handlers
+#[derive(Debug, Deserialize)]
+pub struct ProblemQuery {
+    pub sector_id: Option<u16>,
+    pub min_grade: Option<u8>,
+    pub max_grade: Option<u8>,
+    pub name: Option<String>,
+    pub favorite: Option<bool>,
+    pub project: Option<bool>,
+    pub page: Option<u32>,
+    pub per_page: Option<u32>,
+    // Takes precedence over `page`
+    pub cursor: Option<String>,
+}
+
+// Cursors point after the last listed problem. Problems are kept in ID order and IDs are
+// never reused, so creates and deletes don't shift later pages.
+fn encode_cursor(problem_id: u32) -> String {
+    hex::encode(problem_id.to_be_bytes())
+}
+
+fn decode_cursor(cursor: &str) -> Result<u32, ApiError> {
+    hex::decode(cursor)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .map(u32::from_be_bytes)
+        .ok_or(ApiError::bad_request("INVALID_CURSOR", "Invalid cursor"))
+}
+
+pub async fn list_problems(
+    State(state): State<AppState>,
+    user: Option<AuthUser>,
+    Query(query): Query<ProblemQuery>,
+) -> Result<Json<ProblemList>, ApiError> {
+    let username = user.as_ref().map(|u| u.username.as_str());
+
+    if username.is_none() && (query.favorite.is_some() || query.project.is_some()) {
+        return Err(ApiError::NotAuthenticated);
+    }
+    let after = query.cursor.as_deref().map(decode_cursor).transpose()?;
+
+    let can_see_hidden = user
+        .as_ref()
+        .is_some_and(|u| u.can(Permission::ModerateContent));
+    let marks = ProblemMarks::load(&state, username).await;
+    let problems = state.problems.read().await;
+
+    let candidates: Box<dyn Iterator<Item = &Problem>> = match query.sector_id {
+        Some(sector_id) => Box::new(problems.in_sector(sector_id)),
+        None => Box::new(problems.iter()),
+    };
+    let filtered: Vec<&Problem> = candidates
+        .filter(|p| p.is_visible_to(username, can_see_hidden))
+        .filter(|p| query.min_grade.is_none_or(|g| p.grade >= g))
+        .filter(|p| query.max_grade.is_none_or(|g| p.grade <= g))
+        .filter(|p| {
+            query
+                .name
+                .as_ref()
+                .is_none_or(|name| p.name.to_lowercase().contains(&name.to_lowercase()))
+        })
+        .filter(|p| {
+            query
+                .favorite
+                .is_none_or(|f| marks.favorites.contains(&p.id) == f)
+        })
+        .filter(|p| {
+            query
+                .project
+                .is_none_or(|f| marks.projects.contains(&p.id) == f)
+        })
+        .collect();
+
+    let total = filtered.len() as u32;
+    let per_page = query.per_page.unwrap_or(20).clamp(1, 100);
+    let (skip, page) = match after {
+        Some(after) => (filtered.partition_point(|p| p.id <= after), None),
+        None => {
+            let page = query.page.unwrap_or(1).max(1);
+            ((page - 1) as usize * per_page as usize, Some(page))
+        }
+    };
+
+    let listed: Vec<&Problem> = filtered
+        .iter()
+        .skip(skip)
+        .take(per_page as usize)
+        .copied()
+        .collect();
+    let next_cursor = listed
+        .last()
+        .filter(|_| skip + listed.len() < filtered.len())
+        .map(|p| encode_cursor(p.id));
+
+    Ok(Json(ProblemList {
+        problems: listed.into_iter().map(|p| marks.summary(p)).collect(),
+        total,
+        page,
+        per_page,
+        next_cursor,
+    }))
+}
+
+pub async fn get_problem(
+    State(state): State<AppState>,
+    user: Option<AuthUser>,
+    Path(id): Path<u32>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let username = user.as_ref().map(|u| u.username.as_str());
+    let can_see_hidden = user
+        .as_ref()
+        .is_some_and(|u| u.can(Permission::ModerateContent));
+    let marks = ProblemMarks::load(&state, username).await;
+    let problems = state.problems.read().await;
+
+    let problem = problems
+        .get(id)
+        .filter(|p| p.is_visible_to(username, can_see_hidden))
+        .ok_or(ApiError::NotFound("Problem not found"))?;
+
+    let detail = marks.detail(problem);
+    let etag = detail.etag();
+    if if_none_match(&headers, &etag) {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+    }
+
+    Ok(([(header::ETAG, etag)], Json(detail)).into_response())
+}
+
+// Sync handler
+pub async fn sync(
+    State(state): State<AppState>,
+    user: Option<AuthUser>,
+    Query(query): Query<SyncQuery>,
+) -> Json<SyncResponse> {
+    let username = user.as_ref().map(|u| u.username.as_str());
+    let can_see_hidden = user
+        .as_ref()
+        .is_some_and(|u| u.can(Permission::ModerateContent));
+
+    // Favorites are changed before the problem is marked, so reading the cursor before the
+    // marks makes sure they include every change up to it. Later changes are sent again.
+    let problems = state.problems.read().await;
+    let cursor = state.change_seq();
+    drop(problems);
+
+    let marks = ProblemMarks::load(&state, username).await;
+
+    // A cursor from the future means the server lost data, start over
+    let since = query.since.unwrap_or(0);
+    let reset = since == 0 || since > cursor;
+    let since = if reset { 0 } else { since };
+
+    let problems = state.problems.read().await;
+    let mut changed = Vec::new();
+    let mut grades = Vec::new();
+    let mut deleted = Vec::new();
+    for problem in problems.iter().filter(|p| reset || p.changed_seq > since) {
+        if problem.is_visible_to(username, can_see_hidden) {
+            let (average_grade, average_stars) = problem.averages();
+            changed.push(marks.detail(problem));
+            grades.push(ProblemGrades {
+                problem_id: problem.id,
+                grades: problem.grades.clone(),
+                average_grade,
+                average_stars,
+            });
+        } else if !reset {
+            deleted.push(problem.id);
+        }
+    }
+
+    if !reset {
+        let tombstones = state.tombstones.read().await;
+        deleted.extend(
+            tombstones
+                .iter()
+                .filter(|t| t.seq > since)
+                .map(|t| t.problem_id),
+        );
+    }
+    drop(problems);
+
+    Json(SyncResponse {
+        cursor,
+        reset,
+        problems: changed,
+        grades,
+        deleted,
+    })
+}
+
+// Event stream handlers
+const EVENTS_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Deserialize)]
+pub struct EventsQuery {
+    pub sector_id: Option<u16>,
+}
+
+// Who listens to `/events`, to render each event the way they would see it
+struct Subscriber {
+    state: AppState,
+    username: Option<String>,
+    can_see_hidden: bool,
+    sector_id: Option<u16>,
+}
+
+impl Subscriber {
+    async fn render(&self, event: ProblemEvent) -> Option<Event> {
+        if self.sector_id.is_some_and(|id| id != event.sector_id) {
+            return None;
+        }
+
+        let username = self.username.as_deref();
+        let visible = event.is_visible_to(username, self.can_see_hidden);
+        let deleted = || {
+            Event::default()
+                .event("problem_deleted")
+                .json_data(ProblemDeletedEvent {
+                    problem_id: event.problem_id,
+                    sector_id: event.sector_id,
+                })
+        };
+
+        let rendered = match &event.kind {
+            ProblemEventKind::Deleted => deleted(),
+            // Hiding a problem removes it for everyone who can't see hidden problems
+            ProblemEventKind::Updated(_) if !visible => deleted(),
+            _ if !visible => return None,
+            ProblemEventKind::Created(detail) | ProblemEventKind::Updated(detail) => {
+                let name = match event.kind {
+                    ProblemEventKind::Created(_) => "problem_created",
+                    _ => "problem_updated",
+                };
+                let detail = self.with_marks(detail.clone()).await;
+                Event::default().event(name).json_data(detail)
+            }
+            ProblemEventKind::GradeSubmitted(grade) => {
+                Event::default().event("grade_submitted").json_data(grade)
+            }
+        };
+        rendered.ok()
+    }
+
+    async fn with_marks(&self, mut detail: ProblemDetail) -> ProblemDetail {
+        let users = self.state.users.read().await;
+        if let Some(user) = users
+            .iter()
+            .find(|u| Some(u.username.as_str()) == self.username.as_deref())
+        {
+            detail.is_favorite = user.favorites.contains(&detail.id);
+            detail.is_project = user.projects.contains(&detail.id);
+        }
+        detail
+    }
+}
+
+pub async fn events(
+    State(state): State<AppState>,
+    user: Option<AuthUser>,
+    Query(query): Query<EventsQuery>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let receiver = state.events.subscribe();
+    let subscriber = Subscriber {
+        can_see_hidden: user
+            .as_ref()
+            .is_some_and(|u| u.can(Permission::ModerateContent)),
+        username: user.map(|u| u.username),
+        sector_id: query.sector_id,
+        state,
+    };
+
+    let events = stream::unfold(
+        (receiver, subscriber),
+        |(mut receiver, subscriber)| async move {
+            loop {
+                let event = match receiver.recv().await {
+                    Ok(event) => subscriber.render(event).await,
+                    // Missed events can be caught up on with `/sync`
+                    Err(RecvError::Lagged(_)) => Some(Event::default().event("resync").data("{}")),
+                    Err(RecvError::Closed) => return None,
+                };
+                if let Some(event) = event {
+                    return Some((Ok(event), (receiver, subscriber)));
+                }
+            }
+        },
+    );
+
+    Sse::new(events).keep_alive(KeepAlive::new().interval(EVENTS_KEEP_ALIVE))
+}
+
+fn validate_hold_sequence(hold_sequence: &[Hold]) -> Result<(), ApiError> {
+    if hold_sequence.is_empty() {
+        return Err(ApiError::bad_request(
+            "INVALID_HOLD_SEQUENCE",
+            "Hold sequence cannot be empty",
+        ));
+    }
+    Ok(())
+}
+
+pub async fn create_problem(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Json(payload): Json<CreateProblemRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    if !user.can(Permission::CreateProblem) {
+        return Err(ApiError::forbidden(
+            "FORBIDDEN",
+            "You are not allowed to create problems",
+        ));
+    }
+
+    // Validate that sector_id exists
+    let sector_exists = state
+        .sector_metadata
+        .values()
+        .any(|metadata| metadata.id == Some(payload.sector_id));
+
+    if !sector_exists {
+        return Err(ApiError::bad_request(
+            "INVALID_SECTOR",
+            "Sector does not exist",
+        ));
+    }
+
+    validate_hold_sequence(&payload.hold_sequence)?;
+
+    let id = state.get_next_problem_id().await;
+    let name = payload.name.unwrap_or_else(|| format!("Problem {}", id));
+
+    let mut problem = Problem {
+        id,
+        name,
+        description: payload.description,
+        author: user.username,
+        grade: payload.grade,
+        sector_id: payload.sector_id,
+        hold_sequence: payload.hold_sequence,
+        grades: Vec::new(),
+        comments: Vec::new(),
+        hidden: false,
+        updated_at: now(),
+        version: 1,
+        changed_seq: 0,
+        stats: ProblemStats::default(),
+    };
+
+    let detail = problem.to_detail();
+    let etag = detail.etag();
+
+    let mut problems = state.problems.write().await;
+    problem.changed_seq = state.next_change_seq();
+    state.events.publish(ProblemEvent::created(&problem));
+    problems.insert(problem);
+    drop(problems);
+
+    state.mark_dirty();
+
+    Ok((StatusCode::CREATED, [(header::ETAG, etag)], Json(detail)).into_response())
+}
+
+// Short description of a problem for the audit log
+fn problem_summary(problem: &Problem) -> String {
+    format!(
+        "{} by {}, grade {}, sector {}, {} holds",
+        problem.name,
+        problem.author,
+        problem.grade,
+        problem.sector_id,
+        problem.hold_sequence.len()
+    )
 }
 
 pub async fn update_problem(
     State(state): State<AppState>,
-    headers: HeaderMap,
-    Path(id): Path<u32>,
-    Json(payload): Json<UpdateProblemRequest>,
-) -> Result<impl IntoResponse, Response> {
-    let (username, _) = get_auth_user(&state, &headers).await?;
+    user: AuthUser,
+    ClientIp(ip): ClientIp,
+    Path(id): Path<u32>,
+    headers: HeaderMap,
+    Json(payload): Json<UpdateProblemRequest>,
+) -> Result<Response, ApiError> {
+    apply_problem_patch(&state, &user, ip, id, &headers, payload.into()).await
+}
+
+// Accepts `application/merge-patch+json` as well as `application/json`
+pub async fn patch_problem(
+    State(state): State<AppState>,
+    user: AuthUser,
+    ClientIp(ip): ClientIp,
+    Path(id): Path<u32>,
+    headers: HeaderMap,
+    Json(patch): Json<ProblemPatch>,
+) -> Result<Response, ApiError> {
+    apply_problem_patch(&state, &user, ip, id, &headers, patch).await
+}
+
+async fn apply_problem_patch(
+    state: &AppState,
+    user: &AuthUser,
+    ip: IpAddr,
+    id: u32,
+    headers: &HeaderMap,
+    patch: ProblemPatch,
+) -> Result<Response, ApiError> {
+    let mut problems = state.problems.write().await;
+
+    let mut problem = problems
+        .get_mut(id)
+        .ok_or(ApiError::NotFound("Problem not found"))?;
+
+    if problem.author != user.username && !user.can(Permission::EditAnyProblem) {
+        return Err(ApiError::forbidden(
+            "FORBIDDEN",
+            "You can only edit your own problems",
+        ));
+    }
+    if problem.hidden && !user.can(Permission::ModerateContent) {
+        return Err(content_hidden());
+    }
+
+    check_if_match(headers, problem.version)?;
+
+    // Name and description are optional on creation, grade and holds are not
+    if patch.grade == Some(None) {
+        return Err(ApiError::bad_request(
+            "INVALID_GRADE",
+            "Grade cannot be removed",
+        ));
+    }
+    if let Some(hold_sequence) = &patch.hold_sequence {
+        validate_hold_sequence(hold_sequence.as_deref().unwrap_or_default())?;
+    }
+
+    // Only edits of other users' problems are audited
+    let before = (problem.author != user.username).then(|| problem_summary(&problem));
+
+    if let Some(name) = patch.name {
+        problem.name = name.unwrap_or_else(|| format!("Problem {}", id));
+    }
+    if let Some(description) = patch.description {
+        problem.description = description;
+    }
+    if let Some(Some(grade)) = patch.grade {
+        problem.grade = grade;
+    }
+    if let Some(Some(hold_sequence)) = patch.hold_sequence {
+        problem.hold_sequence = hold_sequence;
+        // Clear grades if hold sequence changes
+        problem.grades.clear();
+    }
+
+    problem.updated_at = now();
+    problem.mark_edited(state.next_change_seq());
+    state.events.publish(ProblemEvent::updated(&problem));
+
+    let after = before.is_some().then(|| problem_summary(&problem));
+    let detail = problem.to_detail();
+    let etag = detail.etag();
+    drop(problem);
+    drop(problems);
+
+    state.mark_dirty();
+
+    if before.is_some() {
+        state
+            .audit_log
+            .record(
+                &user.username,
+                ip,
+                AuditAction::ProblemUpdate,
+                format!("problem:{id}"),
+                before,
+                after,
+            )
+            .await;
+    }
+
+    Ok(([(header::ETAG, etag)], Json(detail)).into_response())
+}
+
+pub async fn delete_problem(
+    State(state): State<AppState>,
+    user: AuthUser,
+    ClientIp(ip): ClientIp,
+    Path(id): Path<u32>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, ApiError> {
+    let mut problems = state.problems.write().await;
+
+    let problem = problems
+        .get(id)
+        .ok_or(ApiError::NotFound("Problem not found"))?;
+
+    if problem.author != user.username && !user.can(Permission::DeleteAnyProblem) {
+        return Err(ApiError::forbidden(
+            "FORBIDDEN",
+            "You can only delete your own problems",
+        ));
+    }
+
+    check_if_match(&headers, problem.version)?;
+
+    let problem = problems
+        .remove(id)
+        .ok_or(ApiError::NotFound("Problem not found"))?;
+    state.add_tombstones(&[id]).await;
+    state.events.publish(ProblemEvent::deleted(&problem));
+    drop(problems);
+
+    let mut users = state.users.write().await;
+    for user in users.iter_mut() {
+        user.favorites.retain(|&p| p != id);
+        user.projects.retain(|&p| p != id);
+    }
+    drop(users);
+
+    let mut circuits = state.circuits.write().await;
+    for circuit in circuits.iter_mut() {
+        circuit.remove_problem(id);
+    }
+    drop(circuits);
+
+    state.mark_dirty();
+
+    state
+        .audit_log
+        .record(
+            &user.username,
+            ip,
+            AuditAction::ProblemDelete,
+            format!("problem:{id}"),
+            Some(problem_summary(&problem)),
+            None,
+        )
+        .await;
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+// Grade handlers
+pub async fn get_problem_grades(
+    State(state): State<AppState>,
+    user: Option<AuthUser>,
+    Path(id): Path<u32>,
+) -> Result<Json<ProblemGrades>, ApiError> {
+    let username = user.as_ref().map(|u| u.username.as_str());
+    let can_see_hidden = user
+        .as_ref()
+        .is_some_and(|u| u.can(Permission::ModerateContent));
+    let problems = state.problems.read().await;
+
+    let problem = problems
+        .get(id)
+        .filter(|p| p.is_visible_to(username, can_see_hidden))
+        .ok_or(ApiError::NotFound("Problem not found"))?;
+
+    let (avg_grade, avg_stars) = problem.averages();
+
+    Ok(Json(ProblemGrades {
+        problem_id: id,
+        grades: problem.grades.clone(),
+        average_grade: avg_grade,
+        average_stars: avg_stars,
+    }))
+}
+
+fn validate_stars(stars: u8) -> Result<(), ApiError> {
+    if !(1..=5).contains(&stars) {
+        return Err(ApiError::bad_request(
+            "INVALID_STARS",
+            "Stars must be between 1 and 5",
+        ));
+    }
+    Ok(())
+}
+
+// Adds or replaces the user's grade of the problem, returns whether it was added
+fn set_grade(
+    problem: &mut Problem,
+    username: &str,
+    payload: &SubmitGradeRequest,
+    created_at: String,
+) -> (bool, Grade) {
+    let grade = Grade {
+        username: username.to_string(),
+        grade: payload.grade,
+        stars: payload.stars,
+        created_at,
+    };
+
+    match problem.grades.iter_mut().find(|g| g.username == username) {
+        Some(existing) => {
+            *existing = grade.clone();
+            (false, grade)
+        }
+        None => {
+            problem.grades.push(grade.clone());
+            (true, grade)
+        }
+    }
+}
+
+pub async fn submit_problem_grade(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Path(id): Path<u32>,
+    Json(payload): Json<SubmitGradeRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    validate_stars(payload.stars)?;
+
+    let mut problems = state.problems.write().await;
+
+    let mut problem = problems
+        .get_mut(id)
+        .filter(|p| p.is_visible_to(Some(&user.username), user.can(Permission::ModerateContent)))
+        .ok_or(ApiError::NotFound("Problem not found"))?;
+
+    let (created, grade) = set_grade(&mut problem, &user.username, &payload, now());
+    let status = if created {
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    };
+    problem.mark_changed(state.next_change_seq());
+    state
+        .events
+        .publish(ProblemEvent::grade_submitted(&problem, grade.clone()));
+
+    drop(problem);
+    drop(problems);
+
+    state.mark_dirty();
+
+    Ok((status, Json(grade)).into_response())
+}
+
+const MAX_BATCH_GRADES: usize = 500;
+const MAX_IDEMPOTENCY_KEY_LENGTH: usize = 128;
+
+pub async fn submit_grades_batch(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Json(payload): Json<BatchGradeRequest>,
+) -> Result<Json<BatchGradeResponse>, ApiError> {
+    let username = &user.username;
+    let can_see_hidden = user.can(Permission::ModerateContent);
+    if payload.grades.len() > MAX_BATCH_GRADES {
+        return Err(ApiError::bad_request(
+            "BATCH_TOO_LARGE",
+            format!("At most {MAX_BATCH_GRADES} grades can be submitted at once"),
+        ));
+    }
+    if payload.grades.iter().any(|item| {
+        item.idempotency_key.is_empty() || item.idempotency_key.len() > MAX_IDEMPOTENCY_KEY_LENGTH
+    }) {
+        return Err(ApiError::bad_request(
+            "INVALID_IDEMPOTENCY_KEY",
+            format!("Idempotency keys must be 1 to {MAX_IDEMPOTENCY_KEY_LENGTH} characters"),
+        ));
+    }
+
+    let now = now_secs();
+    let mut problems = state.problems.write().await;
+    let mut records = state.idempotency_records.write().await;
+
+    let mut results = Vec::with_capacity(payload.grades.len());
+    for item in &payload.grades {
+        let key = (username.clone(), item.idempotency_key.clone());
+        // Expired records are only dropped when saving, until then they are ignored
+        if let Some(record) = records.get(&key).filter(|r| !r.is_expired(now)) {
+            results.push(BatchGradeResult {
+                replayed: true,
+                ..record.result.clone()
+            });
+            continue;
+        }
+
+        let result = apply_batch_grade(&state, &mut problems, username, can_see_hidden, item, now);
+        // Invalid grades and missing problems changed nothing, so a retry is tried again
+        if matches!(
+            result.status,
+            BatchGradeStatus::Created | BatchGradeStatus::Updated | BatchGradeStatus::Conflict
+        ) {
+            records.insert(
+                key,
+                IdempotencyRecord {
+                    username: username.clone(),
+                    idempotency_key: item.idempotency_key.clone(),
+                    result: result.clone(),
+                    created_at: now,
+                },
+            );
+        }
+        results.push(result);
+    }
+    drop(records);
+    drop(problems);
+
+    state.mark_dirty();
+
+    Ok(Json(BatchGradeResponse { results }))
+}
+
+fn apply_batch_grade(
+    state: &AppState,
+    problems: &mut ProblemStore,
+    username: &str,
+    can_see_hidden: bool,
+    item: &BatchGradeItem,
+    now: u64,
+) -> BatchGradeResult {
+    let result = |status, grade, error: Option<ApiError>| BatchGradeResult {
+        idempotency_key: item.idempotency_key.clone(),
+        problem_id: item.problem_id,
+        status,
+        grade,
+        code: error.as_ref().map(|e| e.code().to_string()),
+        error: error.as_ref().map(|e| e.message().to_string()),
+        replayed: false,
+    };
+
+    if let Err(e) = validate_stars(item.submission.stars) {
+        return result(BatchGradeStatus::Invalid, None, Some(e));
+    }
+    let Some(mut problem) = problems
+        .get_mut(item.problem_id)
+        .filter(|p| p.is_visible_to(Some(username), can_see_hidden))
+    else {
+        return result(
+            BatchGradeStatus::NotFound,
+            None,
+            Some(ApiError::NotFound("Problem not found")),
+        );
+    };
+
+    // A client clock running ahead must not make its grades win every conflict
+    let climbed_at = item.climbed_at.min(now);
+    if let Some(existing) = problem.grades.iter().find(|g| g.username == username)
+        && existing.created_at.parse::<u64>().unwrap_or(0) > climbed_at
+    {
+        return result(
+            BatchGradeStatus::Conflict,
+            Some(existing.clone()),
+            Some(ApiError::conflict(
+                "NEWER_GRADE_EXISTS",
+                "A newer grade of this problem already exists",
+            )),
+        );
+    }
+
+    let (created, grade) = set_grade(
+        &mut problem,
+        username,
+        &item.submission,
+        climbed_at.to_string(),
+    );
+    problem.mark_changed(state.next_change_seq());
+    state
+        .events
+        .publish(ProblemEvent::grade_submitted(&problem, grade.clone()));
+
+    let status = if created {
+        BatchGradeStatus::Created
+    } else {
+        BatchGradeStatus::Updated
+    };
+    result(status, Some(grade), None)
+}
+
+// Favorite & project handlers
+#[derive(Clone, Copy, PartialEq)]
+enum UserList {
+    Favorites,
+    Projects,
+}
+
+impl UserList {
+    fn of(self, user: &mut User) -> &mut Vec<u32> {
+        match self {
+            UserList::Favorites => &mut user.favorites,
+            UserList::Projects => &mut user.projects,
+        }
+    }
+}
+
+async fn update_user_problem_list(
+    state: &AppState,
+    user: &AuthUser,
+    id: u32,
+    add: bool,
+    list: UserList,
+) -> Result<StatusCode, ApiError> {
+    let problems = state.problems.read().await;
+    let visible = problems.get(id).is_some_and(|p| {
+        p.is_visible_to(Some(&user.username), user.can(Permission::ModerateContent))
+    });
+    drop(problems);
+
+    let mut users = state.users.write().await;
+    let stored = users
+        .iter_mut()
+        .find(|u| u.username == user.username)
+        .ok_or(ApiError::InvalidToken)?;
+
+    let ids = list.of(stored);
+    // A problem hidden after it was added can still be taken off the list
+    if !visible && (add || !ids.contains(&id)) {
+        return Err(ApiError::NotFound("Problem not found"));
+    }
+    if ids.contains(&id) == add {
+        return Ok(StatusCode::NO_CONTENT);
+    }
+    if add {
+        ids.push(id);
+    } else {
+        ids.retain(|&p| p != id);
+    }
+
+    // The favorite count and marks are part of the problem detail. Users stay locked, so
+    // the count can't get out of step with the lists.
+    let mut problems = state.problems.write().await;
+    if let Some(mut problem) = problems.get_mut(id) {
+        if list == UserList::Favorites {
+            let count = &mut problem.stats.favorite_count;
+            *count = if add {
+                *count + 1
+            } else {
+                count.saturating_sub(1)
+            };
+        }
+        problem.mark_changed(state.next_change_seq());
+        state.events.publish(ProblemEvent::updated(&problem));
+    }
+    drop(problems);
+    drop(users);
+
+    state.mark_dirty();
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn add_favorite(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Path(id): Path<u32>,
+) -> Result<impl IntoResponse, ApiError> {
+    update_user_problem_list(&state, &user, id, true, UserList::Favorites).await
+}
+
+pub async fn remove_favorite(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Path(id): Path<u32>,
+) -> Result<impl IntoResponse, ApiError> {
+    update_user_problem_list(&state, &user, id, false, UserList::Favorites).await
+}
+
+pub async fn add_project(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Path(id): Path<u32>,
+) -> Result<impl IntoResponse, ApiError> {
+    update_user_problem_list(&state, &user, id, true, UserList::Projects).await
+}
+
+pub async fn remove_project(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Path(id): Path<u32>,
+) -> Result<impl IntoResponse, ApiError> {
+    update_user_problem_list(&state, &user, id, false, UserList::Projects).await
+}
+
+// Comment handlers
+fn validate_comment_text(text: &str) -> Result<(), ApiError> {
+    if text.trim().is_empty() || text.len() > 2000 {
+        return Err(ApiError::bad_request(
+            "INVALID_COMMENT",
+            "Comment must be between 1 and 2000 characters",
+        ));
+    }
+    Ok(())
+}
+
+// Authors can't rewrite what moderation hid before it is unhidden
+fn content_hidden() -> ApiError {
+    ApiError::conflict(
+        "CONTENT_HIDDEN",
+        "Hidden by moderation, it can't be edited until it is unhidden",
+    )
+}
+
+fn comment_not_found() -> ApiError {
+    ApiError::NotFound("Comment not found")
+}
+
+pub async fn get_problem_comments(
+    State(state): State<AppState>,
+    user: Option<AuthUser>,
+    Path(id): Path<u32>,
+) -> Result<Json<ProblemComments>, ApiError> {
+    let username = user.as_ref().map(|u| u.username.as_str());
+    let can_see_hidden = user
+        .as_ref()
+        .is_some_and(|u| u.can(Permission::ModerateContent));
+    let problems = state.problems.read().await;
+
+    let problem = problems
+        .get(id)
+        .filter(|p| p.is_visible_to(username, can_see_hidden))
+        .ok_or(ApiError::NotFound("Problem not found"))?;
+
+    // Hidden comments are only shown to their author and admins
+    let comments = problem
+        .comments
+        .iter()
+        .filter(|c| !c.hidden || can_see_hidden || username == Some(c.username.as_str()))
+        .cloned()
+        .collect();
+
+    Ok(Json(ProblemComments {
+        problem_id: id,
+        comments,
+    }))
+}
+
+pub async fn create_problem_comment(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Path(id): Path<u32>,
+    Json(payload): Json<CreateCommentRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    validate_comment_text(&payload.text)?;
+
+    let comment_id = state.get_next_comment_id().await;
+
+    let mut problems = state.problems.write().await;
+
+    let mut problem = problems
+        .get_mut(id)
+        .filter(|p| p.is_visible_to(Some(&user.username), user.can(Permission::ModerateContent)))
+        .ok_or(ApiError::NotFound("Problem not found"))?;
+
+    let created_at = now();
+    let comment = Comment {
+        id: comment_id,
+        username: user.username,
+        text: payload.text,
+        is_beta: payload.is_beta,
+        hidden: false,
+        created_at: created_at.clone(),
+        updated_at: created_at,
+    };
+    problem.comments.push(comment.clone());
+    problem.mark_changed(state.next_change_seq());
+    state.events.publish(ProblemEvent::updated(&problem));
+    drop(problem);
+    drop(problems);
+
+    state.mark_dirty();
+
+    Ok((StatusCode::CREATED, Json(comment)))
+}
+
+// Finds a comment the caller is allowed to edit or delete, i.e. their own or any if moderator
+fn find_owned_comment<'a>(
+    problems: &'a mut ProblemStore,
+    id: u32,
+    comment_id: u32,
+    user: &AuthUser,
+) -> Result<(ProblemMut<'a>, usize), ApiError> {
+    let problem = problems.get_mut(id).ok_or_else(comment_not_found)?;
+    let pos = problem
+        .comments
+        .iter()
+        .position(|c| c.id == comment_id)
+        .ok_or_else(comment_not_found)?;
+
+    if problem.comments[pos].username != user.username && !user.can(Permission::ModerateContent) {
+        return Err(ApiError::forbidden(
+            "FORBIDDEN",
+            "You can only edit your own comments",
+        ));
+    }
+
+    Ok((problem, pos))
+}
+
+// Short description of a comment for the audit log
+fn comment_summary(problem_id: u32, comment: &Comment) -> String {
+    format!(
+        "\"{}\" by {} on problem {}",
+        excerpt(&comment.text),
+        comment.username,
+        problem_id
+    )
+}
+
+pub async fn update_problem_comment(
+    State(state): State<AppState>,
+    user: AuthUser,
+    ClientIp(ip): ClientIp,
+    Path((id, comment_id)): Path<(u32, u32)>,
+    Json(payload): Json<UpdateCommentRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    if let Some(ref text) = payload.text {
+        validate_comment_text(text)?;
+    }
+
+    let mut problems = state.problems.write().await;
+    let (mut problem, pos) = find_owned_comment(&mut problems, id, comment_id, &user)?;
+    if (problem.hidden || problem.comments[pos].hidden) && !user.can(Permission::ModerateContent) {
+        return Err(content_hidden());
+    }
+    let comment = &mut problem.comments[pos];
+
+    // Only edits of other users' comments are audited
+    let before = (comment.username != user.username).then(|| comment_summary(id, comment));
+
+    if let Some(text) = payload.text {
+        comment.text = text;
+    }
+    if let Some(is_beta) = payload.is_beta {
+        comment.is_beta = is_beta;
+    }
+    comment.updated_at = now();
+
+    let comment = comment.clone();
+    drop(problem);
+    drop(problems);
+
+    state.mark_dirty();
+
+    if before.is_some() {
+        state
+            .audit_log
+            .record(
+                &user.username,
+                ip,
+                AuditAction::CommentUpdate,
+                format!("comment:{comment_id}"),
+                before,
+                Some(comment_summary(id, &comment)),
+            )
+            .await;
+    }
+
+    Ok(Json(comment))
+}
+
+pub async fn delete_problem_comment(
+    State(state): State<AppState>,
+    user: AuthUser,
+    ClientIp(ip): ClientIp,
+    Path((id, comment_id)): Path<(u32, u32)>,
+) -> Result<impl IntoResponse, ApiError> {
+    let mut problems = state.problems.write().await;
+    let (mut problem, pos) = find_owned_comment(&mut problems, id, comment_id, &user)?;
+    let comment = problem.comments.remove(pos);
+    problem.mark_changed(state.next_change_seq());
+    state.events.publish(ProblemEvent::updated(&problem));
+    drop(problem);
+    drop(problems);
+
+    state.mark_dirty();
+
+    state
+        .audit_log
+        .record(
+            &user.username,
+            ip,
+            AuditAction::CommentDelete,
+            format!("comment:{comment_id}"),
+            Some(comment_summary(id, &comment)),
+            None,
+        )
+        .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Report handlers
+fn validate_report_reason(reason: &str) -> Result<(), ApiError> {
+    if reason.trim().is_empty() || reason.len() > 500 {
+        return Err(ApiError::bad_request(
+            "INVALID_REASON",
+            "Reason must be between 1 and 500 characters",
+        ));
+    }
+    Ok(())
+}
+
+fn excerpt(text: &str) -> String {
+    text.chars().take(200).collect()
+}
+
+async fn create_report(
+    state: &AppState,
+    user: AuthUser,
+    target: ReportTarget,
+    reason: String,
+) -> Result<(StatusCode, Json<Report>), ApiError> {
+    validate_report_reason(&reason)?;
+    let username = user.username.clone();
+
+    let problems = state.problems.read().await;
+    let visible = |id| {
+        problems
+            .get(id)
+            .filter(|p| p.is_visible_to(Some(&username), user.can(Permission::ModerateContent)))
+    };
+    let content = match target {
+        ReportTarget::Problem { problem_id } => visible(problem_id).map(|p| {
+            (
+                p.author.clone(),
+                excerpt(&match p.description {
+                    Some(ref description) => format!("{}: {}", p.name, description),
+                    None => p.name.clone(),
+                }),
+            )
+        }),
+        ReportTarget::Comment {
+            problem_id,
+            comment_id,
+        } => visible(problem_id).and_then(|p| {
+            p.comments
+                .iter()
+                .find(|c| c.id == comment_id)
+                .map(|c| (c.username.clone(), excerpt(&c.text)))
+        }),
+    };
+    drop(problems);
+
+    let (author, excerpt) = content.ok_or(ApiError::NotFound("Reported content not found"))?;
+
+    let mut reports = state.reports.write().await;
+    if reports
+        .iter()
+        .any(|r| r.status == ReportStatus::Open && r.target == target && r.reporter == username)
+    {
+        return Err(ApiError::conflict(
+            "ALREADY_REPORTED",
+            "You have already reported this content",
+        ));
+    }
+
+    let report = Report {
+        id: state.get_next_report_id().await,
+        reporter: username,
+        target,
+        author,
+        excerpt,
+        reason,
+        status: ReportStatus::Open,
+        created_at: now(),
+        resolved_by: None,
+        resolved_at: None,
+    };
+    reports.push(report.clone());
+    drop(reports);
+
+    state.mark_dirty();
+
+    Ok((StatusCode::CREATED, Json(report)))
+}
+
+pub async fn report_problem(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Path(id): Path<u32>,
+    Json(payload): Json<CreateReportRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let target = ReportTarget::Problem { problem_id: id };
+    create_report(&state, user, target, payload.reason).await
+}
+
+pub async fn report_comment(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Path((id, comment_id)): Path<(u32, u32)>,
+    Json(payload): Json<CreateReportRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let target = ReportTarget::Comment {
+        problem_id: id,
+        comment_id,
+    };
+    create_report(&state, user, target, payload.reason).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReportQuery {
+    pub status: Option<ReportStatus>,
+}
+
+pub async fn list_reports(
+    State(state): State<AppState>,
+    _: Require<ModerateContent>,
+    Query(query): Query<ReportQuery>,
+) -> Result<Json<Vec<Report>>, ApiError> {
+    let status = query.status.unwrap_or(ReportStatus::Open);
+    let reports = state.reports.read().await;
+
+    Ok(Json(
+        reports
+            .iter()
+            .filter(|r| r.status == status)
+            .cloned()
+            .collect(),
+    ))
+}
+
+pub async fn moderate_report(
+    State(state): State<AppState>,
+    Require(moderator, _): Require<ModerateContent>,
+    ClientIp(ip): ClientIp,
+    Path(id): Path<u32>,
+    Json(payload): Json<ModerationRequest>,
+) -> Result<Json<Report>, ApiError> {
+    let reports = state.reports.read().await;
+    let report = reports.iter().find(|r| r.id == id).cloned();
+    drop(reports);
+
+    let report = report.ok_or(ApiError::NotFound("Report not found"))?;
+
+    if report.status != ReportStatus::Open {
+        return Err(ApiError::conflict(
+            "REPORT_CLOSED",
+            "Report has already been handled",
+        ));
+    }
+
+    match payload.action {
+        ModerationAction::Dismiss => {}
+        ModerationAction::Hide => {
+            let mut problems = state.problems.write().await;
+            match report.target {
+                ReportTarget::Problem { problem_id } => {
+                    if let Some(mut problem) = problems.get_mut(problem_id) {
+                        problem.hidden = true;
+                        problem.mark_changed(state.next_change_seq());
+                        state.events.publish(ProblemEvent::updated(&problem));
+                    }
+                }
+                ReportTarget::Comment {
+                    problem_id,
+                    comment_id,
+                } => {
+                    if let Some(mut problem) = problems.get_mut(problem_id)
+                        && let Some(comment) =
+                            problem.comments.iter_mut().find(|c| c.id == comment_id)
+                    {
+                        comment.hidden = true;
+                        problem.mark_changed(state.next_change_seq());
+                        state.events.publish(ProblemEvent::updated(&problem));
+                    }
+                }
+            }
+        }
+        ModerationAction::Ban => {
+            let author_role = state.role_of(&report.author).await;
+            if author_role.can(Permission::ModerateContent) {
+                return Err(ApiError::bad_request(
+                    "CANNOT_BAN_MODERATOR",
+                    "Moderators and admins cannot be banned",
+                ));
+            }
+
+            let mut users = state.users.write().await;
+            if let Some(user) = users.iter_mut().find(|u| u.username == report.author) {
+                user.banned = true;
+            }
+            drop(users);
+
+            let mut sessions = state.sessions.write().await;
+            sessions.remove_user_sessions(&report.author);
+        }
+    }
+
+    let resolved_at = now();
+    let status = match payload.action {
+        ModerationAction::Dismiss => ReportStatus::Dismissed,
+        ModerationAction::Hide | ModerationAction::Ban => ReportStatus::Resolved,
+    };
+
+    // Hiding or banning also handles all other open reports of the same content
+    let mut reports = state.reports.write().await;
+    let mut handled = report.clone();
+    for r in reports.iter_mut() {
+        let affected = r.id == id
+            || (status == ReportStatus::Resolved
+                && r.status == ReportStatus::Open
+                && r.target == report.target);
+        if affected {
+            r.status = status;
+            r.resolved_by = Some(moderator.username.clone());
+            r.resolved_at = Some(resolved_at.clone());
+        }
+        if r.id == id {
+            handled = r.clone();
+        }
+    }
+    drop(reports);
+
+    let mut moderation_log = state.moderation_log.write().await;
+    moderation_log.push(ModerationLogEntry {
+        report_id: id,
+        moderator: moderator.username.clone(),
+        action: payload.action,
+        target: report.target,
+        author: report.author.clone(),
+        note: payload.note.clone(),
+        created_at: resolved_at,
+    });
+    drop(moderation_log);
+
+    state.mark_dirty();
+
+    let after = match payload.note {
+        Some(note) => format!("{} ({note})", api_name(&payload.action)),
+        None => api_name(&payload.action),
+    };
+    state
+        .audit_log
+        .record(
+            &moderator.username,
+            ip,
+            AuditAction::ReportModerate,
+            format!("report:{id}"),
+            Some(format!("\"{}\" by {}", report.excerpt, report.author)),
+            Some(after),
+        )
+        .await;
+
+    Ok(Json(handled))
+}
+
+pub async fn unhide_problem(
+    State(state): State<AppState>,
+    Require(moderator, _): Require<ModerateContent>,
+    ClientIp(ip): ClientIp,
+    Path(id): Path<u32>,
+) -> Result<impl IntoResponse, ApiError> {
+    let mut problems = state.problems.write().await;
+    let mut problem = problems
+        .get_mut(id)
+        .ok_or(ApiError::NotFound("Problem not found"))?;
+    if !problem.hidden {
+        return Err(ApiError::conflict("NOT_HIDDEN", "Problem is not hidden"));
+    }
+    problem.hidden = false;
+    problem.mark_changed(state.next_change_seq());
+    state.events.publish(ProblemEvent::updated(&problem));
+    let summary = problem_summary(&problem);
+    drop(problem);
+    drop(problems);
+
+    state.mark_dirty();
+
+    state
+        .audit_log
+        .record(
+            &moderator.username,
+            ip,
+            AuditAction::ProblemUnhide,
+            format!("problem:{id}"),
+            Some(summary),
+            None,
+        )
+        .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn unhide_comment(
+    State(state): State<AppState>,
+    Require(moderator, _): Require<ModerateContent>,
+    ClientIp(ip): ClientIp,
+    Path((id, comment_id)): Path<(u32, u32)>,
+) -> Result<impl IntoResponse, ApiError> {
+    let mut problems = state.problems.write().await;
+    let mut problem = problems.get_mut(id).ok_or_else(comment_not_found)?;
+    let comment = problem
+        .comments
+        .iter_mut()
+        .find(|c| c.id == comment_id)
+        .ok_or_else(comment_not_found)?;
+    if !comment.hidden {
+        return Err(ApiError::conflict("NOT_HIDDEN", "Comment is not hidden"));
+    }
+    comment.hidden = false;
+    let summary = comment_summary(id, comment);
+    problem.mark_changed(state.next_change_seq());
+    state.events.publish(ProblemEvent::updated(&problem));
+    drop(problem);
+    drop(problems);
+
+    state.mark_dirty();
+
+    state
+        .audit_log
+        .record(
+            &moderator.username,
+            ip,
+            AuditAction::CommentUnhide,
+            format!("comment:{comment_id}"),
+            Some(summary),
+            None,
+        )
+        .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn unban_user(
+    State(state): State<AppState>,
+    Require(moderator, _): Require<ModerateContent>,
+    ClientIp(ip): ClientIp,
+    Path(username): Path<String>,
+) -> Result<impl IntoResponse, ApiError> {
+    let mut users = state.users.write().await;
+    let user = users
+        .iter_mut()
+        .find(|u| u.username == username)
+        .ok_or(ApiError::NotFound("User not found"))?;
+    if !user.banned {
+        return Err(ApiError::conflict("NOT_BANNED", "User is not banned"));
+    }
+    user.banned = false;
+    drop(users);
+
+    state.mark_dirty();
+
+    state
+        .audit_log
+        .record(
+            &moderator.username,
+            ip,
+            AuditAction::UserUnban,
+            format!("user:{username}"),
+            None,
+            None,
+        )
+        .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn get_moderation_log(
+    State(state): State<AppState>,
+    _: Require<ModerateContent>,
+) -> Result<Json<Vec<ModerationLogEntry>>, ApiError> {
+    let moderation_log = state.moderation_log.read().await;
+    Ok(Json(moderation_log.clone()))
+}
 
-    let mut problems = state.problems.write().await;
+pub async fn get_audit_log(
+    State(state): State<AppState>,
+    _: Require<ManageUsers>,
+    Query(query): Query<AuditLogQuery>,
+) -> Json<Vec<AuditEntry>> {
+    Json(state.audit_log.query(&query).await)
+}
 
-    let problem = problems.iter_mut().find(|p| p.id == id).ok_or_else(|| {
-        (
-            StatusCode::NOT_FOUND,
-            Json(ErrorResponse {
-                error: "Problem not found".to_string(),
-                code: "NOT_FOUND".to_string(),
-                timeout: None,
-            }),
+// Registration admin handlers
+pub async fn list_invites(
+    State(state): State<AppState>,
+    _: Require<ManageUsers>,
+) -> Result<Json<Vec<InviteCode>>, ApiError> {
+    let invites = state.invites.read().await;
+    Ok(Json(invites.clone()))
+}
+
+pub async fn create_invite(
+    State(state): State<AppState>,
+    Require(admin, _): Require<ManageUsers>,
+    ClientIp(ip): ClientIp,
+    Json(payload): Json<CreateInviteRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let max_uses = payload.max_uses.unwrap_or(1);
+    if max_uses == 0 {
+        return Err(ApiError::bad_request(
+            "INVALID_MAX_USES",
+            "Invite code must allow at least one use",
+        ));
+    }
+
+    let now = now_secs();
+    let invite = InviteCode {
+        code: generate_short_code(),
+        created_by: admin.username,
+        max_uses,
+        uses: 0,
+        expires_at: payload.expires_in.map(|secs| (now + secs).to_string()),
+        created_at: now.to_string(),
+    };
+
+    let mut invites = state.invites.write().await;
+    // Drop codes that can never be used again
+    invites.retain(|i| i.is_valid(now));
+    invites.push(invite.clone());
+    drop(invites);
+
+    state.mark_dirty();
+
+    state
+        .audit_log
+        .record(
+            &invite.created_by,
+            ip,
+            AuditAction::InviteCreate,
+            format!("invite:{}", invite.code),
+            None,
+            Some(format!("{} uses", invite.max_uses)),
         )
-            .into_response()
-    })?;
+        .await;
 
-    let is_admin = state.is_admin(&username);
-    if problem.author != username && !is_admin {
-        return Err((
-            StatusCode::FORBIDDEN,
-            Json(ErrorResponse {
-                error: "You can only edit your own problems".to_string(),
-                code: "FORBIDDEN".to_string(),
-                timeout: None,
-            }),
+    Ok((StatusCode::CREATED, Json(invite)))
+}
+
+pub async fn delete_invite(
+    State(state): State<AppState>,
+    Require(admin, _): Require<ManageUsers>,
+    ClientIp(ip): ClientIp,
+    Path(code): Path<String>,
+) -> Result<impl IntoResponse, ApiError> {
+    let mut invites = state.invites.write().await;
+    let pos = invites
+        .iter()
+        .position(|i| i.code == code)
+        .ok_or(ApiError::NotFound("Invite code not found"))?;
+    let invite = invites.remove(pos);
+    drop(invites);
+
+    state.mark_dirty();
+
+    state
+        .audit_log
+        .record(
+            &admin.username,
+            ip,
+            AuditAction::InviteDelete,
+            format!("invite:{code}"),
+            Some(format!("{}/{} uses", invite.uses, invite.max_uses)),
+            None,
         )
-            .into_response());
+        .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn list_pending_users(
+    State(state): State<AppState>,
+    _: Require<ManageUsers>,
+) -> Result<Json<Vec<String>>, ApiError> {
+    let users = state.users.read().await;
+    Ok(Json(
+        users
+            .iter()
+            .filter(|u| u.pending_approval)
+            .map(|u| u.username.clone())
+            .collect(),
+    ))
+}
+
+fn pending_user_not_found() -> ApiError {
+    ApiError::NotFound("No pending user with this username")
+}
+
+pub async fn approve_pending_user(
+    State(state): State<AppState>,
+    Require(admin, _): Require<ManageUsers>,
+    ClientIp(ip): ClientIp,
+    Path(username): Path<String>,
+) -> Result<impl IntoResponse, ApiError> {
+    let mut users = state.users.write().await;
+    let user = users
+        .iter_mut()
+        .find(|u| u.username == username && u.pending_approval)
+        .ok_or_else(pending_user_not_found)?;
+    user.pending_approval = false;
+    drop(users);
+
+    state.mark_dirty();
+
+    state
+        .audit_log
+        .record(
+            &admin.username,
+            ip,
+            AuditAction::UserApprove,
+            format!("user:{username}"),
+            None,
+            None,
+        )
+        .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn reject_pending_user(
+    State(state): State<AppState>,
+    Require(admin, _): Require<ManageUsers>,
+    ClientIp(ip): ClientIp,
+    Path(username): Path<String>,
+) -> Result<impl IntoResponse, ApiError> {
+    let mut users = state.users.write().await;
+    let pos = users
+        .iter()
+        .position(|u| u.username == username && u.pending_approval)
+        .ok_or_else(pending_user_not_found)?;
+    users.remove(pos);
+    drop(users);
+
+    state.mark_dirty();
+
+    state
+        .audit_log
+        .record(
+            &admin.username,
+            ip,
+            AuditAction::UserReject,
+            format!("user:{username}"),
+            None,
+            None,
+        )
+        .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Role handlers
+pub async fn list_roles(
+    State(state): State<AppState>,
+    _: Require<ManageUsers>,
+) -> Json<Vec<UserRole>> {
+    let users = state.users.read().await;
+    let roles = users
+        .iter()
+        .map(|u| UserRole {
+            username: u.username.clone(),
+            role: if state.is_admin(&u.username) {
+                Role::Admin
+            } else {
+                u.role
+            },
+        })
+        .collect();
+
+    Json(roles)
+}
+
+pub async fn set_user_role(
+    State(state): State<AppState>,
+    Require(admin, _): Require<ManageUsers>,
+    ClientIp(ip): ClientIp,
+    Path(username): Path<String>,
+    Json(payload): Json<UpdateRoleRequest>,
+) -> Result<Json<UserRole>, ApiError> {
+    if username == admin.username || state.is_admin(&username) {
+        return Err(ApiError::bad_request(
+            "ROLE_LOCKED",
+            "Role of this user can't be changed",
+        ));
     }
 
-    if let Some(ref seq) = payload.hold_sequence {
-        if seq.is_empty() {
-            return Err((
-                StatusCode::BAD_REQUEST,
-                Json(ErrorResponse {
-                    error: "Hold sequence cannot be empty".to_string(),
-                    code: "INVALID_HOLD_SEQUENCE".to_string(),
-                    timeout: None,
-                }),
-            )
-                .into_response());
-        }
+    let mut users = state.users.write().await;
+    let user = users
+        .iter_mut()
+        .find(|u| u.username == username)
+        .ok_or(ApiError::NotFound("User not found"))?;
+    let previous = std::mem::replace(&mut user.role, payload.role);
+    drop(users);
+
+    state.mark_dirty();
+
+    state
+        .audit_log
+        .record(
+            &admin.username,
+            ip,
+            AuditAction::RoleChange,
+            format!("user:{username}"),
+            Some(api_name(&previous)),
+            Some(api_name(&payload.role)),
+        )
+        .await;
+
+    Ok(Json(UserRole {
+        username,
+        role: payload.role,
+    }))
+}
+
+// Password reset handlers
+const RESET_CODE_LIFETIME: u64 = 24 * 60 * 60;
+
+pub async fn create_reset_code(
+    State(state): State<AppState>,
+    Require(admin, _): Require<ManageUsers>,
+    ClientIp(ip): ClientIp,
+    Path(username): Path<String>,
+) -> Result<(StatusCode, Json<ResetCode>), ApiError> {
+    // A reset code would let another admin take over the accounts named in the settings
+    if state.is_admin(&username) {
+        return Err(ApiError::bad_request(
+            "ROLE_LOCKED",
+            "Password of this user can't be reset",
+        ));
     }
 
-    if let Some(name) = payload.name {
-        problem.name = name;
+    let mut users = state.users.write().await;
+    let user = users
+        .iter_mut()
+        .find(|u| u.username == username)
+        .ok_or(ApiError::NotFound("User not found"))?;
+
+    // Issuing a new code replaces the previous one
+    let code = generate_short_code();
+    let expires_at = (now_secs() + RESET_CODE_LIFETIME).to_string();
+    user.password_reset = Some(PasswordReset {
+        code_hash: hash_password(&code, &user.salt),
+        expires_at: expires_at.clone(),
+    });
+    drop(users);
+
+    state.mark_dirty();
+
+    state
+        .audit_log
+        .record(
+            &admin.username,
+            ip,
+            AuditAction::ResetCodeCreate,
+            format!("user:{username}"),
+            None,
+            None,
+        )
+        .await;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(ResetCode {
+            username,
+            code,
+            expires_at,
+        }),
+    ))
+}
+
+pub async fn delete_reset_code(
+    State(state): State<AppState>,
+    Require(admin, _): Require<ManageUsers>,
+    ClientIp(ip): ClientIp,
+    Path(username): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let mut users = state.users.write().await;
+    let user = users
+        .iter_mut()
+        .find(|u| u.username == username && u.password_reset.is_some())
+        .ok_or(ApiError::NotFound("No reset code for this user"))?;
+    user.password_reset = None;
+    drop(users);
+
+    state.mark_dirty();
+
+    state
+        .audit_log
+        .record(
+            &admin.username,
+            ip,
+            AuditAction::ResetCodeDelete,
+            format!("user:{username}"),
+            None,
+            None,
+        )
+        .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Login lockout handlers
+pub async fn list_lockouts(
+    State(state): State<AppState>,
+    _: Require<ManageUsers>,
+) -> Json<Vec<Lockout>> {
+    let rate_limiter = state.rate_limiter.read().await;
+    Json(rate_limiter.lockouts())
+}
+
+pub async fn lift_lockout(
+    State(state): State<AppState>,
+    Require(admin, _): Require<ManageUsers>,
+    ClientIp(ip): ClientIp,
+    Path((kind, value)): Path<(String, String)>,
+) -> Result<StatusCode, ApiError> {
+    let key = match kind.as_str() {
+        "ip" => LockoutKey::Ip(
+            value
+                .parse()
+                .map_err(|_| ApiError::bad_request("INVALID_IP", "Invalid IP address"))?,
+        ),
+        "username" => LockoutKey::Username(value.clone()),
+        _ => return Err(ApiError::NotFound("Unknown lockout type")),
+    };
+
+    let mut rate_limiter = state.rate_limiter.write().await;
+    if !rate_limiter.lift(&key) {
+        return Err(ApiError::NotFound("No failed login attempts recorded"));
     }
-    if let Some(description) = payload.description {
-        problem.description = Some(description);
+    drop(rate_limiter);
+
+    state.mark_dirty();
+
+    state
+        .audit_log
+        .record(
+            &admin.username,
+            ip,
+            AuditAction::LockoutLift,
+            format!("lockout:{kind}/{value}"),
+            None,
+            None,
+        )
+        .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Circuit handlers
+fn circuit_not_found() -> ApiError {
+    ApiError::NotFound("Circuit not found")
+}
+
+fn validate_circuit_name(name: &str) -> Result<(), ApiError> {
+    if name.trim().is_empty() || name.len() > 100 {
+        return Err(ApiError::bad_request(
+            "INVALID_NAME",
+            "Circuit name must be between 1 and 100 characters",
+        ));
     }
-    if let Some(grade) = payload.grade {
-        problem.grade = grade;
+    Ok(())
+}
+
+// Problems already in the circuit may stay even if they were hidden since. Callers keep the
+// problems locked until the circuit is stored, so a problem deleted meanwhile is removed from
+// it again by `delete_problem`.
+fn validate_circuit_problems(
+    problems: &ProblemStore,
+    problem_ids: &[u32],
+    current: &[u32],
+    user: &AuthUser,
+) -> Result<(), ApiError> {
+    let unique: HashSet<&u32> = problem_ids.iter().collect();
+    if unique.len() != problem_ids.len() {
+        return Err(ApiError::bad_request(
+            "DUPLICATE_PROBLEM",
+            "Circuit cannot contain the same problem twice",
+        ));
     }
-    if let Some(hold_sequence) = payload.hold_sequence {
-        problem.hold_sequence = hold_sequence;
-        // Clear grades if hold sequence changes
-        problem.grades.clear();
+
+    let can_see_hidden = user.can(Permission::ModerateContent);
+    let missing = problem_ids.iter().find(|&&id| {
+        !current.contains(&id)
+            && !problems
+                .get(id)
+                .is_some_and(|p| p.is_visible_to(Some(&user.username), can_see_hidden))
+    });
+    if let Some(missing) = missing {
+        return Err(ApiError::bad_request(
+            "INVALID_PROBLEM",
+            format!("Problem {} does not exist", missing),
+        ));
     }
 
-    problem.updated_at = now();
+    Ok(())
+}
 
-    let detail = problem.to_detail();
+pub async fn list_circuits(
+    State(state): State<AppState>,
+    user: Option<AuthUser>,
+) -> Result<Json<Vec<CircuitDetail>>, ApiError> {
+    let username = user.as_ref().map(|u| u.username.as_str());
+    let can_see_private = user
+        .as_ref()
+        .is_some_and(|u| u.can(Permission::ManageCircuits));
+
+    let circuits = state.circuits.read().await;
+    let visible = circuits
+        .iter()
+        .filter(|c| c.is_visible_to(username, can_see_private))
+        .map(|c| c.to_detail())
+        .collect();
+
+    Ok(Json(visible))
+}
+
+pub async fn get_circuit(
+    State(state): State<AppState>,
+    user: Option<AuthUser>,
+    Path(id): Path<u32>,
+) -> Result<Json<CircuitDetail>, ApiError> {
+    let username = user.as_ref().map(|u| u.username.as_str());
+    let can_see_private = user
+        .as_ref()
+        .is_some_and(|u| u.can(Permission::ManageCircuits));
+
+    let circuits = state.circuits.read().await;
+    let circuit = circuits
+        .iter()
+        .find(|c| c.id == id && c.is_visible_to(username, can_see_private))
+        .ok_or_else(circuit_not_found)?;
+
+    Ok(Json(circuit.to_detail()))
+}
+
+pub async fn create_circuit(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Json(payload): Json<CreateCircuitRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    validate_circuit_name(&payload.name)?;
+
+    let problems = state.problems.read().await;
+    validate_circuit_problems(&problems, &payload.problem_ids, &[], &user)?;
+
+    let circuit = Circuit {
+        id: state.get_next_circuit_id().await,
+        name: payload.name,
+        owner: user.username,
+        problem_ids: payload.problem_ids,
+        visibility: payload.visibility,
+        updated_at: now(),
+        progress: HashMap::new(),
+    };
+
+    let detail = circuit.to_detail();
+
+    let mut circuits = state.circuits.write().await;
+    circuits.push(circuit);
+    drop(circuits);
     drop(problems);
 
     state.mark_dirty();
 
-    Ok(Json(detail).into_response())
+    Ok((StatusCode::CREATED, Json(detail)))
 }
 
-pub async fn delete_problem(
+// Finds a circuit the caller is allowed to modify
+fn find_owned_circuit<'a>(
+    circuits: &'a mut [Circuit],
+    id: u32,
+    user: &AuthUser,
+) -> Result<&'a mut Circuit, ApiError> {
+    let can_manage = user.can(Permission::ManageCircuits);
+    let circuit = circuits
+        .iter_mut()
+        .find(|c| c.id == id && c.is_visible_to(Some(&user.username), can_manage))
+        .ok_or(ApiError::NotFound("Circuit not found"))?;
+
+    if circuit.owner != user.username && !can_manage {
+        return Err(ApiError::forbidden(
+            "FORBIDDEN",
+            "You can only edit your own circuits",
+        ));
+    }
+
+    Ok(circuit)
+}
+
+// Short description of a circuit for the audit log
+fn circuit_summary(circuit: &Circuit) -> String {
+    format!(
+        "{} by {}, {} problems, {}",
+        circuit.name,
+        circuit.owner,
+        circuit.problem_ids.len(),
+        api_name(&circuit.visibility)
+    )
+}
+
+pub async fn update_circuit(
     State(state): State<AppState>,
-    headers: HeaderMap,
+    user: AuthUser,
+    ClientIp(ip): ClientIp,
     Path(id): Path<u32>,
-) -> Result<impl IntoResponse, Response> {
-    let (username, _) = get_auth_user(&state, &headers).await?;
+    Json(payload): Json<UpdateCircuitRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    if let Some(ref name) = payload.name {
+        validate_circuit_name(name)?;
+    }
 
-    let mut problems = state.problems.write().await;
+    let problems = state.problems.read().await;
+    let mut circuits = state.circuits.write().await;
+    let circuit = find_owned_circuit(&mut circuits, id, &user)?;
+    if let Some(ref problem_ids) = payload.problem_ids {
+        validate_circuit_problems(&problems, problem_ids, &circuit.problem_ids, &user)?;
+    }
 
-    let pos = problems.iter().position(|p| p.id == id).ok_or_else(|| {
-        (
-            StatusCode::NOT_FOUND,
-            Json(ErrorResponse {
-                error: "Problem not found".to_string(),
-                code: "NOT_FOUND".to_string(),
-                timeout: None,
-            }),
-        )
-            .into_response()
-    })?;
+    // Only edits of other users' circuits are audited
+    let before = (circuit.owner != user.username).then(|| circuit_summary(circuit));
 
-    let is_admin = state.is_admin(&username);
-    if problems[pos].author != username && !is_admin {
-        return Err((
-            StatusCode::FORBIDDEN,
-            Json(ErrorResponse {
-                error: "You can only delete your own problems".to_string(),
-                code: "FORBIDDEN".to_string(),
-                timeout: None,
-            }),
-        )
-            .into_response());
+    if let Some(name) = payload.name {
+        circuit.name = name;
     }
+    if let Some(visibility) = payload.visibility {
+        circuit.visibility = visibility;
+    }
+    if let Some(problem_ids) = payload.problem_ids {
+        // Progress only keeps problems that are still part of the circuit
+        for completed in circuit.progress.values_mut() {
+            completed.retain(|p| problem_ids.contains(p));
+        }
+        circuit.problem_ids = problem_ids;
+    }
+
+    circuit.updated_at = now();
 
-    problems.remove(pos);
+    let after = before.is_some().then(|| circuit_summary(circuit));
+    let detail = circuit.to_detail();
+    drop(circuits);
     drop(problems);
 
     state.mark_dirty();
 
-    Ok(StatusCode::NO_CONTENT.into_response())
+    if before.is_some() {
+        state
+            .audit_log
+            .record(
+                &user.username,
+                ip,
+                AuditAction::CircuitUpdate,
+                format!("circuit:{id}"),
+                before,
+                after,
+            )
+            .await;
+    }
+
+    Ok(Json(detail))
 }
 
-// Grade handlers
-pub async fn get_problem_grades(
+pub async fn reorder_circuit(
     State(state): State<AppState>,
+    user: AuthUser,
     Path(id): Path<u32>,
-) -> Result<Json<ProblemGrades>, (StatusCode, Json<ErrorResponse>)> {
-    let problems = state.problems.read().await;
+    Json(payload): Json<ReorderCircuitRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let mut circuits = state.circuits.write().await;
+    let circuit = find_owned_circuit(&mut circuits, id, &user)?;
+
+    let mut current = circuit.problem_ids.clone();
+    let mut requested = payload.problem_ids.clone();
+    current.sort_unstable();
+    requested.sort_unstable();
+    if current != requested {
+        return Err(ApiError::bad_request(
+            "INVALID_ORDER",
+            "New order must contain exactly the problems of the circuit",
+        ));
+    }
 
-    let problem = problems.iter().find(|p| p.id == id).ok_or_else(|| {
-        (
-            StatusCode::NOT_FOUND,
-            Json(ErrorResponse {
-                error: "Problem not found".to_string(),
-                code: "NOT_FOUND".to_string(),
-                timeout: None,
-            }),
-        )
-    })?;
+    circuit.problem_ids = payload.problem_ids;
+    circuit.updated_at = now();
 
-    let (avg_grade, avg_stars) = problem.calculate_averages();
+    let detail = circuit.to_detail();
+    drop(circuits);
 
-    Ok(Json(ProblemGrades {
-        problem_id: id,
-        grades: problem.grades.clone(),
-        average_grade: avg_grade,
-        average_stars: avg_stars,
-    }))
+    state.mark_dirty();
+
+    Ok(Json(detail))
 }
 
-pub async fn submit_problem_grade(
+pub async fn delete_circuit(
     State(state): State<AppState>,
-    headers: HeaderMap,
+    user: AuthUser,
+    ClientIp(ip): ClientIp,
     Path(id): Path<u32>,
-    Json(payload): Json<SubmitGradeRequest>,
-) -> Result<impl IntoResponse, Response> {
-    let (username, _) = get_auth_user(&state, &headers).await?;
-
-    if payload.stars < 1 || payload.stars > 5 {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            Json(ErrorResponse {
-                error: "Stars must be between 1 and 5".to_string(),
-                code: "INVALID_STARS".to_string(),
-                timeout: None,
-            }),
-        )
-            .into_response());
-    }
+) -> Result<impl IntoResponse, ApiError> {
+    let mut circuits = state.circuits.write().await;
+    let summary = circuit_summary(find_owned_circuit(&mut circuits, id, &user)?);
+    circuits.retain(|c| c.id != id);
+    drop(circuits);
 
-    let mut problems = state.problems.write().await;
+    state.mark_dirty();
 
-    let problem = problems.iter_mut().find(|p| p.id == id).ok_or_else(|| {
-        (
-            StatusCode::NOT_FOUND,
-            Json(ErrorResponse {
-                error: "Problem not found".to_string(),
-                code: "NOT_FOUND".to_string(),
-                timeout: None,
-            }),
+    state
+        .audit_log
+        .record(
+            &user.username,
+            ip,
+            AuditAction::CircuitDelete,
+            format!("circuit:{id}"),
+            Some(summary),
+            None,
         )
-            .into_response()
-    })?;
+        .await;
 
-    let existing_pos = problem.grades.iter().position(|g| g.username == username);
+    Ok(StatusCode::NO_CONTENT)
+}
 
-    let (status, grade) = if let Some(pos) = existing_pos {
-        problem.grades[pos].grade = payload.grade;
-        problem.grades[pos].stars = payload.stars;
-        problem.grades[pos].created_at = now();
-        (StatusCode::OK, problem.grades[pos].clone())
-    } else {
-        let grade = Grade {
-            username: username.clone(),
-            grade: payload.grade,
-            stars: payload.stars,
-            created_at: now(),
-        };
-        problem.grades.push(grade.clone());
-        (StatusCode::CREATED, grade)
-    };
+pub async fn get_circuit_progress(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Path(id): Path<u32>,
+) -> Result<Json<CircuitProgress>, ApiError> {
+    let can_see_private = user.can(Permission::ManageCircuits);
 
-    drop(problems);
+    let circuits = state.circuits.read().await;
+    let circuit = circuits
+        .iter()
+        .find(|c| c.id == id && c.is_visible_to(Some(&user.username), can_see_private))
+        .ok_or_else(circuit_not_found)?;
+
+    Ok(Json(circuit.progress_of(&user.username)))
+}
+
+async fn update_circuit_progress(
+    state: &AppState,
+    user: AuthUser,
+    id: u32,
+    problem_id: Option<u32>,
+    completed: bool,
+) -> Result<Json<CircuitProgress>, ApiError> {
+    let can_see_private = user.can(Permission::ManageCircuits);
+    let username = user.username;
+
+    let mut circuits = state.circuits.write().await;
+    let circuit = circuits
+        .iter_mut()
+        .find(|c| c.id == id && c.is_visible_to(Some(&username), can_see_private))
+        .ok_or_else(circuit_not_found)?;
+
+    match problem_id {
+        Some(problem_id) => {
+            if !circuit.problem_ids.contains(&problem_id) {
+                return Err(ApiError::NotFound("Problem is not part of this circuit"));
+            }
+
+            let done = circuit.progress.entry(username.clone()).or_default();
+            if completed && !done.contains(&problem_id) {
+                done.push(problem_id);
+            } else if !completed {
+                done.retain(|&p| p != problem_id);
+            }
+            if done.is_empty() {
+                circuit.progress.remove(&username);
+            }
+        }
+        None => {
+            circuit.progress.remove(&username);
+        }
+    }
+
+    let progress = circuit.progress_of(&username);
+    drop(circuits);
 
     state.mark_dirty();
 
-    Ok((status, Json(grade)).into_response())
+    Ok(Json(progress))
+}
+
+pub async fn complete_circuit_problem(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Path((id, problem_id)): Path<(u32, u32)>,
+) -> Result<Json<CircuitProgress>, ApiError> {
+    update_circuit_progress(&state, user, id, Some(problem_id), true).await
+}
+
+pub async fn uncomplete_circuit_problem(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Path((id, problem_id)): Path<(u32, u32)>,
+) -> Result<Json<CircuitProgress>, ApiError> {
+    update_circuit_progress(&state, user, id, Some(problem_id), false).await
+}
+
+pub async fn reset_circuit_progress(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Path(id): Path<u32>,
+) -> Result<Json<CircuitProgress>, ApiError> {
+    update_circuit_progress(&state, user, id, None, false).await
 }
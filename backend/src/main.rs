@@ -1,25 +1,45 @@
+#[cfg(feature = "embed-web")]
+use axum::handler::HandlerWithoutStateExt;
 use axum::{
     Router,
-    extract::ConnectInfo,
     http::{Request, Response, header},
-    routing::{delete, get, post, put},
+    middleware,
+    routing::{delete, get, patch, post, put},
 };
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::time::Duration;
+#[cfg(feature = "compression")]
+use tower_http::compression::{
+    CompressionLayer,
+    predicate::{NotForContentType, Predicate, SizeAbove},
+};
 use tower_http::cors::{Any, CorsLayer};
 use tower_http::services::ServeDir;
 use tower_http::trace::TraceLayer;
 use tracing::info;
 
+mod audit;
 mod auth;
+mod client_ip;
+mod error;
+mod events;
 mod handlers;
 mod models;
+#[cfg(feature = "oidc")]
+mod oidc;
+mod problem_store;
 mod rate_limit;
 mod state;
+mod web;
 
+use client_ip::ClientIp;
 use state::AppState;
 
+// Smaller responses gain too little from compression to be worth it
+#[cfg(feature = "compression")]
+const COMPRESSION_MIN_SIZE: u16 = 1024;
+
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt()
@@ -56,16 +76,42 @@ async fn main() {
     let cors = CorsLayer::new()
         .allow_origin(Any)
         .allow_methods(Any)
-        .allow_headers(vec![header::AUTHORIZATION, header::CONTENT_TYPE]);
+        .allow_headers(vec![
+            header::AUTHORIZATION,
+            header::CONTENT_TYPE,
+            header::IF_MATCH,
+            header::IF_NONE_MATCH,
+        ])
+        .expose_headers(vec![header::ETAG]);
 
     const API_V1_AUTH: &str = "/api/v1/auth";
     const API_V1_SECTORS: &str = "/api/v1/sectors";
     const API_V1_SECTORS_ID: &str = "/api/v1/sectors/{id}";
     const API_V1_PROBLEMS: &str = "/api/v1/problems";
     const API_V1_PROBLEMS_ID: &str = "/api/v1/problems/{id}";
+    const API_V1_ADMIN: &str = "/api/v1/admin";
+    const API_V1_CIRCUITS: &str = "/api/v1/circuits";
+    const API_V1_CIRCUITS_ID: &str = "/api/v1/circuits/{id}";
+    const API_V1_SYNC: &str = "/api/v1/sync";
+    const API_V1_EVENTS: &str = "/api/v1/events";
+    const API_V1_GRADES_BATCH: &str = "/api/v1/grades/batch";
+
+    let page = ServeDir::new(page_path)
+        .precompressed_br()
+        .precompressed_gzip();
+    // Files in `page/` take precedence over the embedded ones
+    #[cfg(feature = "embed-web")]
+    let page = page.fallback(web::serve_embedded.into_service());
+    #[cfg(feature = "embed-web")]
+    if !web::has_embedded_app() {
+        eprintln!("Built with embed-web, but without the web app build, only serving page/");
+    }
+    let web_app = Router::new()
+        .fallback_service(page)
+        .layer(middleware::from_fn(web::serve_app));
 
     let app = Router::new()
-        .fallback_service(ServeDir::new(page_path))
+        .fallback_service(web_app)
         .route(
             &format!("{}/register", API_V1_AUTH),
             post(handlers::register),
@@ -76,16 +122,47 @@ async fn main() {
             &format!("{}/rotate_token", API_V1_AUTH),
             get(handlers::rotate_token),
         )
+        .route(
+            &format!("{}/change_password", API_V1_AUTH),
+            post(handlers::change_password),
+        )
+        .route(
+            &format!("{}/reset_password", API_V1_AUTH),
+            post(handlers::reset_password),
+        )
+        .route(
+            &format!("{}/tokens", API_V1_AUTH),
+            get(handlers::list_api_tokens),
+        )
+        .route(
+            &format!("{}/tokens", API_V1_AUTH),
+            post(handlers::create_api_token),
+        )
+        .route(
+            &format!("{}/tokens/{{id}}", API_V1_AUTH),
+            delete(handlers::revoke_api_token),
+        )
+        .route(
+            &format!("{}/account", API_V1_AUTH),
+            delete(handlers::delete_account),
+        )
+        .route(
+            &format!("{}/export", API_V1_AUTH),
+            get(handlers::export_account),
+        )
         .route(API_V1_SECTORS, get(handlers::list_sectors))
         .route(API_V1_SECTORS_ID, get(handlers::get_sector))
         .route(
             &format!("{}/image", API_V1_SECTORS_ID),
             get(handlers::get_sector_image),
         )
+        .route(API_V1_SYNC, get(handlers::sync))
+        .route(API_V1_EVENTS, get(handlers::events))
         .route(API_V1_PROBLEMS, get(handlers::list_problems))
         .route(API_V1_PROBLEMS, post(handlers::create_problem))
         .route(API_V1_PROBLEMS_ID, get(handlers::get_problem))
         .route(API_V1_PROBLEMS_ID, put(handlers::update_problem))
+        .route(API_V1_PROBLEMS_ID, patch(handlers::patch_problem))
         .route(API_V1_PROBLEMS_ID, delete(handlers::delete_problem))
         .route(
             &format!("{}/grades", API_V1_PROBLEMS_ID),
@@ -95,7 +172,177 @@ async fn main() {
             &format!("{}/grades", API_V1_PROBLEMS_ID),
             post(handlers::submit_problem_grade),
         )
-        .with_state(state)
+        .route(API_V1_GRADES_BATCH, post(handlers::submit_grades_batch))
+        .route(
+            &format!("{}/comments", API_V1_PROBLEMS_ID),
+            get(handlers::get_problem_comments),
+        )
+        .route(
+            &format!("{}/comments", API_V1_PROBLEMS_ID),
+            post(handlers::create_problem_comment),
+        )
+        .route(
+            &format!("{}/comments/{{comment_id}}", API_V1_PROBLEMS_ID),
+            put(handlers::update_problem_comment),
+        )
+        .route(
+            &format!("{}/comments/{{comment_id}}", API_V1_PROBLEMS_ID),
+            delete(handlers::delete_problem_comment),
+        )
+        .route(
+            &format!("{}/comments/{{comment_id}}/report", API_V1_PROBLEMS_ID),
+            post(handlers::report_comment),
+        )
+        .route(
+            &format!("{}/report", API_V1_PROBLEMS_ID),
+            post(handlers::report_problem),
+        )
+        .route(
+            &format!("{}/favorite", API_V1_PROBLEMS_ID),
+            put(handlers::add_favorite),
+        )
+        .route(
+            &format!("{}/favorite", API_V1_PROBLEMS_ID),
+            delete(handlers::remove_favorite),
+        )
+        .route(
+            &format!("{}/project", API_V1_PROBLEMS_ID),
+            put(handlers::add_project),
+        )
+        .route(
+            &format!("{}/project", API_V1_PROBLEMS_ID),
+            delete(handlers::remove_project),
+        )
+        .route(
+            &format!("{}/invites", API_V1_ADMIN),
+            get(handlers::list_invites),
+        )
+        .route(
+            &format!("{}/invites", API_V1_ADMIN),
+            post(handlers::create_invite),
+        )
+        .route(
+            &format!("{}/invites/{{code}}", API_V1_ADMIN),
+            delete(handlers::delete_invite),
+        )
+        .route(
+            &format!("{}/pending_users", API_V1_ADMIN),
+            get(handlers::list_pending_users),
+        )
+        .route(
+            &format!("{}/pending_users/{{username}}/approve", API_V1_ADMIN),
+            post(handlers::approve_pending_user),
+        )
+        .route(
+            &format!("{}/pending_users/{{username}}", API_V1_ADMIN),
+            delete(handlers::reject_pending_user),
+        )
+        .route(
+            &format!("{}/reset_codes/{{username}}", API_V1_ADMIN),
+            post(handlers::create_reset_code),
+        )
+        .route(
+            &format!("{}/reset_codes/{{username}}", API_V1_ADMIN),
+            delete(handlers::delete_reset_code),
+        )
+        .route(
+            &format!("{}/tokens", API_V1_ADMIN),
+            get(handlers::list_all_api_tokens),
+        )
+        .route(
+            &format!("{}/tokens/{{id}}", API_V1_ADMIN),
+            delete(handlers::revoke_any_api_token),
+        )
+        .route(
+            &format!("{}/lockouts", API_V1_ADMIN),
+            get(handlers::list_lockouts),
+        )
+        .route(
+            &format!("{}/lockouts/{{kind}}/{{value}}", API_V1_ADMIN),
+            delete(handlers::lift_lockout),
+        )
+        .route(
+            &format!("{}/roles", API_V1_ADMIN),
+            get(handlers::list_roles),
+        )
+        .route(
+            &format!("{}/roles/{{username}}", API_V1_ADMIN),
+            put(handlers::set_user_role),
+        )
+        .route(
+            &format!("{}/reports", API_V1_ADMIN),
+            get(handlers::list_reports),
+        )
+        .route(
+            &format!("{}/reports/{{id}}/action", API_V1_ADMIN),
+            post(handlers::moderate_report),
+        )
+        .route(
+            &format!("{}/problems/{{id}}/unhide", API_V1_ADMIN),
+            post(handlers::unhide_problem),
+        )
+        .route(
+            &format!(
+                "{}/problems/{{id}}/comments/{{comment_id}}/unhide",
+                API_V1_ADMIN
+            ),
+            post(handlers::unhide_comment),
+        )
+        .route(
+            &format!("{}/users/{{username}}/unban", API_V1_ADMIN),
+            post(handlers::unban_user),
+        )
+        .route(
+            &format!("{}/moderation_log", API_V1_ADMIN),
+            get(handlers::get_moderation_log),
+        )
+        .route(
+            &format!("{}/audit_log", API_V1_ADMIN),
+            get(handlers::get_audit_log),
+        )
+        .route(API_V1_CIRCUITS, get(handlers::list_circuits))
+        .route(API_V1_CIRCUITS, post(handlers::create_circuit))
+        .route(API_V1_CIRCUITS_ID, get(handlers::get_circuit))
+        .route(API_V1_CIRCUITS_ID, put(handlers::update_circuit))
+        .route(API_V1_CIRCUITS_ID, delete(handlers::delete_circuit))
+        .route(
+            &format!("{}/order", API_V1_CIRCUITS_ID),
+            put(handlers::reorder_circuit),
+        )
+        .route(
+            &format!("{}/progress", API_V1_CIRCUITS_ID),
+            get(handlers::get_circuit_progress),
+        )
+        .route(
+            &format!("{}/progress", API_V1_CIRCUITS_ID),
+            delete(handlers::reset_circuit_progress),
+        )
+        .route(
+            &format!("{}/progress/{{problem_id}}", API_V1_CIRCUITS_ID),
+            put(handlers::complete_circuit_problem),
+        )
+        .route(
+            &format!("{}/progress/{{problem_id}}", API_V1_CIRCUITS_ID),
+            delete(handlers::uncomplete_circuit_problem),
+        );
+
+    #[cfg(feature = "oidc")]
+    let app = app
+        .route(
+            &format!("{}/oidc/login", API_V1_AUTH),
+            get(handlers::oidc_login),
+        )
+        .route(
+            &format!("{}/oidc/callback", API_V1_AUTH),
+            get(handlers::oidc_callback),
+        );
+
+    let app = app
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            rate_limit::limit_requests,
+        ))
+        .with_state(state.clone())
         .layer(
             TraceLayer::new_for_http()
                 .on_request(|request: &Request<_>, _span: &tracing::Span| {
@@ -103,8 +350,8 @@ async fn main() {
                     let uri = request.uri().path();
                     let ip = request
                         .extensions()
-                        .get::<ConnectInfo<SocketAddr>>()
-                        .map(|ConnectInfo(addr)| addr.ip().to_string())
+                        .get::<ClientIp>()
+                        .map(|ClientIp(ip)| ip.to_string())
                         .unwrap_or_else(|| "unknown".to_string());
                     info!("{} {} from {}", method, uri, ip);
                 })
@@ -120,8 +367,23 @@ async fn main() {
                     },
                 ),
         )
+        .layer(middleware::from_fn_with_state(
+            state,
+            client_ip::resolve_client_ip,
+        ))
         .layer(cors);
 
+    // Event streams are left alone, compressing them would hold back events
+    #[cfg(feature = "compression")]
+    let app = app.layer(
+        CompressionLayer::new().compress_when(
+            SizeAbove::new(COMPRESSION_MIN_SIZE)
+                .and(NotForContentType::GRPC)
+                .and(NotForContentType::IMAGES)
+                .and(NotForContentType::SSE),
+        ),
+    );
+
     let port = if cfg!(debug_assertions) { 3000 } else { 80 };
     let addr = format!("0.0.0.0:{}", port);
 
@@ -1,5 +1,8 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::net::IpAddr;
 
 // Settings (read-only, loaded on startup)
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -7,20 +10,468 @@ pub struct Settings {
     pub ap_name: String,
     pub ap_password: String,
     pub admin_users: Vec<String>,
+    #[serde(default)]
+    pub registration_mode: RegistrationMode,
+    #[serde(default)]
+    pub account_deletion: AccountDeletion,
+    #[serde(default)]
+    pub rate_limits: RateLimits,
+    #[serde(default)]
+    pub login_limits: LoginLimits,
+    // Reverse proxies (CIDR) whose forwarding header is believed
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    // Header the trusted proxies set, the other one is ignored
+    #[serde(default)]
+    pub forwarded_header: ForwardedHeader,
+    // Login through an OpenID Connect provider, disabled if not set
+    #[serde(default)]
+    pub oidc: Option<OidcSettings>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OidcSettings {
+    // Discovery document is fetched from {issuer}/.well-known/openid-configuration
+    pub issuer: String,
+    pub client_id: String,
+    #[serde(default)]
+    pub client_secret: Option<String>,
+    // Must point to /api/v1/auth/oidc/callback of this server
+    pub redirect_uri: String,
+    #[serde(default = "OidcSettings::default_scopes")]
+    pub scopes: Vec<String>,
+    // ID token claim used as the username of provisioned users
+    #[serde(default = "OidcSettings::default_username_claim")]
+    pub username_claim: String,
+    // Create a user on first login, otherwise only linked users can log in
+    #[serde(default = "OidcSettings::default_auto_provision")]
+    pub auto_provision: bool,
+    // Link an existing local user with the same username on first login.
+    // Only enable this if the provider's usernames can be trusted to match.
+    #[serde(default)]
+    pub link_existing_users: bool,
+    // Where the callback sends the browser, with the session in the URL fragment.
+    // The callback responds with JSON if not set.
+    #[serde(default)]
+    pub post_login_redirect: Option<String>,
+}
+
+impl OidcSettings {
+    fn default_scopes() -> Vec<String> {
+        vec!["openid".to_string(), "profile".to_string()]
+    }
+
+    fn default_username_claim() -> String {
+        "preferred_username".to_string()
+    }
+
+    fn default_auto_provision() -> bool {
+        true
+    }
+}
+
+// Failed login handling, tracked separately per IP and per username
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoginLimits {
+    pub username: AttemptLimits,
+    // Lenient by default, many users can share one IP behind a NAT
+    pub ip: AttemptLimits,
+}
+
+impl Default for LoginLimits {
+    fn default() -> Self {
+        Self {
+            username: AttemptLimits {
+                wait_multiplier: 3,
+                ban_threshold: 5,
+                ban_duration: 2 * 60 * 60,
+            },
+            ip: AttemptLimits {
+                wait_multiplier: 1,
+                ban_threshold: 20,
+                ban_duration: 2 * 60 * 60,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct AttemptLimits {
+    // Seconds to wait after a failure, multiplied by the number of failures
+    pub wait_multiplier: u64,
+    // Failures until a ban
+    pub ban_threshold: u32,
+    // Seconds
+    pub ban_duration: u64,
+}
+
+// Token bucket limits per route group, a missing group is not limited
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimits {
+    // register, login and password reset
+    pub auth: Option<BucketConfig>,
+    // every other POST, PUT, PATCH and DELETE under /api
+    pub write: Option<BucketConfig>,
+    // GET requests under /api
+    pub read: Option<BucketConfig>,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            // A whole gym can share one IP behind its NAT, login lockouts stop guessing
+            auth: Some(BucketConfig {
+                capacity: 60,
+                per_minute: 30,
+                key: BucketKey::Ip,
+            }),
+            // Per user, so members on the gym's Wi-Fi don't use up each other's budget
+            write: Some(BucketConfig {
+                capacity: 30,
+                per_minute: 30,
+                key: BucketKey::User,
+            }),
+            read: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct BucketConfig {
+    // Burst size
+    pub capacity: u32,
+    // Tokens refilled per minute
+    pub per_minute: u32,
+    #[serde(default)]
+    pub key: BucketKey,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BucketKey {
+    #[default]
+    Ip,
+    // Authenticated user, anonymous requests fall back to their IP
+    User,
+    // Separate buckets for the IP and the user, both need a token
+    Both,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RegistrationMode {
+    #[default]
+    Open,
+    InviteCode,
+    AdminApproval,
+    Closed,
+}
+
+// What happens to problems, grades, comments and circuits of a deleted account
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountDeletion {
+    // Kept, with the author replaced by `DELETED_USERNAME`
+    #[default]
+    Anonymize,
+    Delete,
+}
+
+// Header carrying the client address through reverse proxies
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardedHeader {
+    #[default]
+    XForwardedFor,
+    Forwarded,
+}
+
+// Placeholder author of content left behind by deleted accounts, can't be registered
+pub const DELETED_USERNAME: &str = "[deleted]";
+
 // User
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
     pub username: String,
     pub password_hash: String,
     pub salt: String,
+    #[serde(default)]
+    pub favorites: Vec<u32>,
+    #[serde(default)]
+    pub projects: Vec<u32>,
+    #[serde(default)]
+    pub banned: bool,
+    // Registered in admin-approval mode and not approved yet
+    #[serde(default)]
+    pub pending_approval: bool,
+    #[serde(default)]
+    pub role: Role,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_reset: Option<PasswordReset>,
+    // `sub` of the linked OpenID Connect account
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub oidc_subject: Option<String>,
+}
+
+// Role
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    Member,
+    Setter,
+    Moderator,
+    Admin,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    CreateProblem,
+    EditAnyProblem,
+    DeleteAnyProblem,
+    // View, edit and delete circuits of other users
+    ManageCircuits,
+    // See hidden content, edit/delete any comment and handle reports
+    ModerateContent,
+    // Registration, invite codes and roles
+    ManageUsers,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateRoleRequest {
+    pub role: Role,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserRole {
+    pub username: String,
+    pub role: Role,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct RegisterRequest {
     pub username: String,
     pub password: String,
+    pub invite_code: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PendingRegistration {
+    pub username: String,
+    pub pending_approval: bool,
+}
+
+// Invite code
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InviteCode {
+    pub code: String,
+    pub created_by: String,
+    pub max_uses: u32,
+    pub uses: u32,
+    pub expires_at: Option<String>,
+    pub created_at: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateInviteRequest {
+    pub max_uses: Option<u32>,
+    // Seconds until the code expires, never if not set
+    pub expires_in: Option<u64>,
+}
+
+// One-time reset code issued by an admin, hashed with the user's salt
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PasswordReset {
+    pub code_hash: String,
+    pub expires_at: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ResetCode {
+    pub username: String,
+    pub code: String,
+    pub expires_at: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResetPasswordRequest {
+    pub username: String,
+    pub code: String,
+    pub new_password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteAccountRequest {
+    #[serde(default)]
+    pub password: String,
+}
+
+// Everything stored about a user, returned by the export endpoint
+#[derive(Debug, Serialize)]
+pub struct AccountExport {
+    pub exported_at: String,
+    pub username: String,
+    pub role: Role,
+    pub favorites: Vec<u32>,
+    pub projects: Vec<u32>,
+    pub problems: Vec<ProblemDetail>,
+    pub grades: Vec<ExportedGrade>,
+    pub comments: Vec<ExportedComment>,
+    pub circuits: Vec<CircuitDetail>,
+    pub circuit_progress: Vec<CircuitProgress>,
+    pub reports: Vec<Report>,
+    pub api_tokens: Vec<ApiTokenInfo>,
+    pub audit_log: Vec<AuditEntry>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExportedGrade {
+    pub problem_id: u32,
+    #[serde(flatten)]
+    pub grade: Grade,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExportedComment {
+    pub problem_id: u32,
+    #[serde(flatten)]
+    pub comment: Comment,
+}
+
+// What failed login attempts are counted against
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "lowercase")]
+pub enum LockoutKey {
+    Ip(IpAddr),
+    Username(String),
+}
+
+// Persisted in lockouts.json, so bans survive restarts
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoginAttempts {
+    pub key: LockoutKey,
+    pub count: u32,
+    pub last_attempt: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Lockout {
+    #[serde(flatten)]
+    pub key: LockoutKey,
+    pub failed_attempts: u32,
+    pub last_attempt: String,
+    pub banned: bool,
+    // When the next login attempt is allowed, None if it already is
+    pub locked_until: Option<String>,
+}
+
+// API token
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenScope {
+    // GET requests only
+    ReadOnly,
+    // Also creates, edits and deletes problems
+    Setter,
+    // Also submits grades and circuit progress, for the tablet at the board
+    BoardController,
+}
+
+impl TokenScope {
+    // API tokens never reach account or admin endpoints, whatever their scope
+    pub fn allows(self, method: &str, path: &str) -> bool {
+        let Some(path) = path.strip_prefix("/api/v1/") else {
+            return true;
+        };
+        if path.starts_with("auth/") || path.starts_with("admin/") {
+            return false;
+        }
+        if method == "GET" || method == "HEAD" {
+            return true;
+        }
+
+        let segments: Vec<&str> = path.trim_end_matches('/').split('/').collect();
+        match self {
+            TokenScope::ReadOnly => false,
+            TokenScope::Setter => matches!(segments[..], ["problems"] | ["problems", _]),
+            TokenScope::BoardController => matches!(
+                segments[..],
+                ["problems", _, "grades"] | ["grades", "batch"] | ["circuits", _, "progress", ..]
+            ),
+        }
+    }
+
+    // Permissions of the owner's role that the token may use
+    pub fn permits(self, permission: Permission) -> bool {
+        match self {
+            TokenScope::ReadOnly | TokenScope::BoardController => false,
+            TokenScope::Setter => matches!(
+                permission,
+                Permission::CreateProblem
+                    | Permission::EditAnyProblem
+                    | Permission::DeleteAnyProblem
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiToken {
+    pub id: u32,
+    pub name: String,
+    pub owner: String,
+    pub scope: TokenScope,
+    // SHA256 of the token, which is only shown once on creation
+    pub token_hash: String,
+    pub created_at: String,
+    // Updated in memory, only saved together with other changes
+    #[serde(default)]
+    pub last_used_at: Option<String>,
+}
+
+impl ApiToken {
+    pub fn to_info(&self) -> ApiTokenInfo {
+        ApiTokenInfo {
+            id: self.id,
+            name: self.name.clone(),
+            owner: self.owner.clone(),
+            scope: self.scope,
+            created_at: self.created_at.clone(),
+            last_used_at: self.last_used_at.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApiTokenInfo {
+    pub id: u32,
+    pub name: String,
+    pub owner: String,
+    pub scope: TokenScope,
+    pub created_at: String,
+    pub last_used_at: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CreatedApiToken {
+    pub token: String,
+    #[serde(flatten)]
+    pub info: ApiTokenInfo,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateApiTokenRequest {
+    pub name: String,
+    pub scope: TokenScope,
 }
 
 #[derive(Debug, Deserialize)]
@@ -34,6 +485,8 @@ pub struct LoginResponse {
     pub token: String,
     pub username: String,
     pub is_admin: bool,
+    pub role: Role,
+    pub permissions: Vec<Permission>,
 }
 
 // Hold Type
@@ -60,7 +513,65 @@ pub struct Problem {
     pub sector_id: u16,
     pub hold_sequence: Vec<Hold>,
     pub grades: Vec<Grade>,
+    #[serde(default)]
+    pub comments: Vec<Comment>,
+    // Hidden by moderation, only visible to the author and admins
+    #[serde(default)]
+    pub hidden: bool,
     pub updated_at: String,
+    // Bumped when the problem itself is edited, checked against `If-Match`. Grades, comments
+    // and favorites don't count, they can't conflict with an edit.
+    #[serde(default)]
+    pub version: u32,
+    // Change sequence of the last change, see `AppState::next_change_seq`
+    #[serde(default)]
+    pub changed_seq: u64,
+    #[serde(skip)]
+    pub stats: ProblemStats,
+}
+
+// Aggregates shown in every summary, cached instead of recomputed on each request
+#[derive(Debug, Clone, Default)]
+pub struct ProblemStats {
+    pub average_grade: Option<f32>,
+    pub average_stars: Option<f32>,
+    pub comment_count: u32,
+    // Favorites are stored with the users, so this is counted on load and kept up to date
+    // where favorites change instead of in `refresh_stats`
+    pub favorite_count: u32,
+}
+
+// Left behind by a deleted problem, so syncing clients learn about the deletion
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tombstone {
+    pub problem_id: u32,
+    pub seq: u64,
+    pub deleted_at: String,
+}
+
+// Id counters that can't be derived from the stored data, saved to counters.json
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Counters {
+    // Deleted comments leave nothing behind, but reports and the audit log still name them
+    pub next_comment_id: u32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SyncQuery {
+    pub since: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SyncResponse {
+    // Pass as `since` on the next sync
+    pub cursor: u64,
+    // The cursor was unknown, so this is a full sync and the local copy must be replaced
+    pub reset: bool,
+    pub problems: Vec<ProblemDetail>,
+    pub grades: Vec<ProblemGrades>,
+    // Deleted problems and problems that are no longer visible to the caller
+    pub deleted: Vec<u32>,
 }
 
 #[derive(Debug, Serialize)]
@@ -73,10 +584,14 @@ pub struct ProblemSummary {
     pub sector_id: u16,
     pub average_grade: Option<f32>,
     pub average_stars: Option<f32>,
+    pub favorite_count: u32,
+    pub is_favorite: bool,
+    pub is_project: bool,
+    pub comment_count: u32,
     pub updated_at: String,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct ProblemDetail {
     pub id: u32,
     pub name: String,
@@ -87,15 +602,24 @@ pub struct ProblemDetail {
     pub hold_sequence: Vec<Hold>,
     pub average_grade: Option<f32>,
     pub average_stars: Option<f32>,
+    pub favorite_count: u32,
+    pub is_favorite: bool,
+    pub is_project: bool,
+    pub comment_count: u32,
     pub updated_at: String,
+    pub version: u32,
 }
 
 #[derive(Debug, Serialize)]
 pub struct ProblemList {
     pub problems: Vec<ProblemSummary>,
     pub total: u32,
-    pub page: u32,
+    // Only for page based requests
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page: Option<u32>,
     pub per_page: u32,
+    // Pass as `cursor` to get the next page, none on the last page
+    pub next_cursor: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -115,6 +639,40 @@ pub struct UpdateProblemRequest {
     pub hold_sequence: Option<Vec<Hold>>,
 }
 
+// JSON Merge Patch (RFC 7396) of a problem: a missing field is unchanged, `null` clears it
+#[derive(Debug, Deserialize)]
+pub struct ProblemPatch {
+    #[serde(default, deserialize_with = "nullable")]
+    pub name: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub description: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub grade: Option<Option<u8>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub hold_sequence: Option<Option<Vec<Hold>>>,
+}
+
+// Tells an explicit `null` (Some(None)) apart from a missing field (None)
+fn nullable<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+// PUT only sets fields, it can't clear them
+impl From<UpdateProblemRequest> for ProblemPatch {
+    fn from(request: UpdateProblemRequest) -> Self {
+        Self {
+            name: request.name.map(Some),
+            description: request.description.map(Some),
+            grade: request.grade.map(Some),
+            hold_sequence: request.hold_sequence.map(Some),
+        }
+    }
+}
+
 // Grade
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Grade {
@@ -132,12 +690,306 @@ pub struct ProblemGrades {
     pub average_stars: Option<f32>,
 }
 
+// Payloads of `/events` besides the problem detail
+#[derive(Debug, Clone, Serialize)]
+pub struct ProblemDeletedEvent {
+    pub problem_id: u32,
+    pub sector_id: u16,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GradeSubmittedEvent {
+    pub problem_id: u32,
+    pub sector_id: u16,
+    pub grade: Grade,
+    pub average_grade: Option<f32>,
+    pub average_stars: Option<f32>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct SubmitGradeRequest {
     pub grade: u8,
     pub stars: u8,
 }
 
+// Grades collected offline, submitted together once back online
+#[derive(Debug, Deserialize)]
+pub struct BatchGradeRequest {
+    pub grades: Vec<BatchGradeItem>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BatchGradeItem {
+    // Chosen by the client, a retried item with the same key isn't applied again
+    pub idempotency_key: String,
+    pub problem_id: u32,
+    #[serde(flatten)]
+    pub submission: SubmitGradeRequest,
+    // When the problem was climbed, as unix seconds
+    pub climbed_at: u64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchGradeStatus {
+    Created,
+    Updated,
+    // A newer grade already exists and was kept
+    Conflict,
+    Invalid,
+    NotFound,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchGradeResult {
+    pub idempotency_key: String,
+    pub problem_id: u32,
+    pub status: BatchGradeStatus,
+    // The stored grade, for a conflict the newer one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub grade: Option<Grade>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    // Result of an earlier submission with the same key
+    #[serde(default)]
+    pub replayed: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchGradeResponse {
+    pub results: Vec<BatchGradeResult>,
+}
+
+// Result of a batch item, kept for a while to answer retries the same way
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IdempotencyRecord {
+    pub username: String,
+    pub idempotency_key: String,
+    pub result: BatchGradeResult,
+    pub created_at: u64,
+}
+
+// Offline clients may only retry days later
+const IDEMPOTENCY_KEY_LIFETIME: u64 = 7 * 24 * 60 * 60;
+
+impl IdempotencyRecord {
+    pub fn is_expired(&self, now: u64) -> bool {
+        now.saturating_sub(self.created_at) >= IDEMPOTENCY_KEY_LIFETIME
+    }
+}
+
+// Idempotency records by username and key
+pub type IdempotencyRecords = HashMap<(String, String), IdempotencyRecord>;
+
+// Comment
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Comment {
+    pub id: u32,
+    pub username: String,
+    pub text: String,
+    // Contains beta/spoilers, clients hide these by default
+    pub is_beta: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub hidden: bool,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProblemComments {
+    pub problem_id: u32,
+    pub comments: Vec<Comment>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateCommentRequest {
+    pub text: String,
+    #[serde(default)]
+    pub is_beta: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateCommentRequest {
+    pub text: Option<String>,
+    pub is_beta: Option<bool>,
+}
+
+// Report
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ReportTarget {
+    Problem { problem_id: u32 },
+    Comment { problem_id: u32, comment_id: u32 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportStatus {
+    Open,
+    Dismissed,
+    Resolved,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Report {
+    pub id: u32,
+    pub reporter: String,
+    pub target: ReportTarget,
+    // Author of the reported content at the time of reporting
+    pub author: String,
+    // Snapshot of the reported content, so it can be reviewed even after edits
+    pub excerpt: String,
+    pub reason: String,
+    pub status: ReportStatus,
+    pub created_at: String,
+    pub resolved_by: Option<String>,
+    pub resolved_at: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateReportRequest {
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ModerationAction {
+    Dismiss,
+    Hide,
+    Ban,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ModerationRequest {
+    pub action: ModerationAction,
+    pub note: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModerationLogEntry {
+    pub report_id: u32,
+    pub moderator: String,
+    pub action: ModerationAction,
+    pub target: ReportTarget,
+    pub author: String,
+    pub note: Option<String>,
+    pub created_at: String,
+}
+
+// Audit log
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    ProblemUpdate,
+    ProblemDelete,
+    CommentUpdate,
+    CommentDelete,
+    CircuitUpdate,
+    CircuitDelete,
+    ReportModerate,
+    ProblemUnhide,
+    CommentUnhide,
+    UserUnban,
+    RoleChange,
+    UserApprove,
+    UserReject,
+    AccountDelete,
+    InviteCreate,
+    InviteDelete,
+    ResetCodeCreate,
+    ResetCodeDelete,
+    ApiTokenRevoke,
+    LockoutLift,
+}
+
+// Appended to audit_log.jsonl, entries are never changed or removed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub id: u64,
+    pub actor: String,
+    pub action: AuditAction,
+    // Kind and id of the affected object, e.g. `problem:12` or `user:alice`
+    pub target: String,
+    pub before: Option<String>,
+    pub after: Option<String>,
+    pub ip: String,
+    pub created_at: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuditLogQuery {
+    pub actor: Option<String>,
+    pub action: Option<AuditAction>,
+    // Either a full target like `problem:12` or only its kind like `problem`
+    pub target: Option<String>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+// Circuit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CircuitVisibility {
+    #[default]
+    Public,
+    Private,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Circuit {
+    pub id: u32,
+    pub name: String,
+    pub owner: String,
+    pub problem_ids: Vec<u32>,
+    pub visibility: CircuitVisibility,
+    pub updated_at: String,
+    // username -> completed problem ids
+    #[serde(default)]
+    pub progress: HashMap<String, Vec<u32>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CircuitDetail {
+    pub id: u32,
+    pub name: String,
+    pub owner: String,
+    pub problem_ids: Vec<u32>,
+    pub visibility: CircuitVisibility,
+    pub updated_at: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateCircuitRequest {
+    pub name: String,
+    #[serde(default)]
+    pub problem_ids: Vec<u32>,
+    #[serde(default)]
+    pub visibility: CircuitVisibility,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateCircuitRequest {
+    pub name: Option<String>,
+    pub problem_ids: Option<Vec<u32>>,
+    pub visibility: Option<CircuitVisibility>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReorderCircuitRequest {
+    pub problem_ids: Vec<u32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CircuitProgress {
+    pub circuit_id: u32,
+    pub completed: Vec<u32>,
+    pub total: u32,
+    pub next_problem_id: Option<u32>,
+}
+
 // Sector
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SectorMetadata {
@@ -180,8 +1032,53 @@ pub struct ErrorResponse {
     pub timeout: Option<u64>,
 }
 
+impl Role {
+    pub fn permissions(self) -> &'static [Permission] {
+        use Permission::*;
+        match self {
+            Role::Member => &[CreateProblem],
+            Role::Setter => &[CreateProblem, EditAnyProblem],
+            Role::Moderator => &[
+                CreateProblem,
+                EditAnyProblem,
+                DeleteAnyProblem,
+                ManageCircuits,
+                ModerateContent,
+            ],
+            Role::Admin => &[
+                CreateProblem,
+                EditAnyProblem,
+                DeleteAnyProblem,
+                ManageCircuits,
+                ModerateContent,
+                ManageUsers,
+            ],
+        }
+    }
+
+    pub fn can(self, permission: Permission) -> bool {
+        self.permissions().contains(&permission)
+    }
+}
+
+impl LoginResponse {
+    pub fn new(token: String, username: String, role: Role) -> Self {
+        Self {
+            token,
+            username,
+            is_admin: role == Role::Admin,
+            role,
+            permissions: role.permissions().to_vec(),
+        }
+    }
+}
+
 impl Problem {
-    pub fn calculate_averages(&self) -> (Option<f32>, Option<f32>) {
+    pub fn is_visible_to(&self, username: Option<&str>, can_see_hidden: bool) -> bool {
+        !self.hidden || can_see_hidden || username.is_some_and(|u| u == self.author)
+    }
+
+    fn calculate_averages(&self) -> (Option<f32>, Option<f32>) {
         if self.grades.is_empty() {
             return (None, None);
         }
@@ -194,8 +1091,19 @@ impl Problem {
         (Some(avg_grade), Some(avg_stars))
     }
 
+    pub fn refresh_stats(&mut self) {
+        let (average_grade, average_stars) = self.calculate_averages();
+        self.stats.average_grade = average_grade;
+        self.stats.average_stars = average_stars;
+        self.stats.comment_count = self.comments.iter().filter(|c| !c.hidden).count() as u32;
+    }
+
+    pub fn averages(&self) -> (Option<f32>, Option<f32>) {
+        (self.stats.average_grade, self.stats.average_stars)
+    }
+
     pub fn to_summary(&self) -> ProblemSummary {
-        let (avg_grade, avg_stars) = self.calculate_averages();
+        let (avg_grade, avg_stars) = self.averages();
         ProblemSummary {
             id: self.id,
             name: self.name.clone(),
@@ -205,12 +1113,29 @@ impl Problem {
             sector_id: self.sector_id,
             average_grade: avg_grade,
             average_stars: avg_stars,
+            favorite_count: self.stats.favorite_count,
+            is_favorite: false,
+            is_project: false,
+            comment_count: self.stats.comment_count,
             updated_at: self.updated_at.clone(),
         }
     }
 
+    // Records a change of anything shown with the problem. Every change goes through here,
+    // so this is also where the cached stats are refreshed.
+    pub fn mark_changed(&mut self, seq: u64) {
+        self.changed_seq = seq;
+        self.refresh_stats();
+    }
+
+    // Records an edit of the problem itself, see `version`
+    pub fn mark_edited(&mut self, seq: u64) {
+        self.version += 1;
+        self.mark_changed(seq);
+    }
+
     pub fn to_detail(&self) -> ProblemDetail {
-        let (avg_grade, avg_stars) = self.calculate_averages();
+        let (avg_grade, avg_stars) = self.averages();
         ProblemDetail {
             id: self.id,
             name: self.name.clone(),
@@ -221,7 +1146,76 @@ impl Problem {
             hold_sequence: self.hold_sequence.clone(),
             average_grade: avg_grade,
             average_stars: avg_stars,
+            favorite_count: self.stats.favorite_count,
+            is_favorite: false,
+            is_project: false,
+            comment_count: self.stats.comment_count,
+            updated_at: self.updated_at.clone(),
+            version: self.version,
+        }
+    }
+}
+
+impl ProblemDetail {
+    // Edit version followed by a hash of everything else, so grades, comments and the
+    // caller's favorites invalidate cached copies without failing `If-Match`
+    pub fn etag(&self) -> String {
+        let mut hasher = DefaultHasher::new();
+        serde_json::to_string(self)
+            .unwrap_or_default()
+            .hash(&mut hasher);
+        format!("\"{}-{:016x}\"", self.version, hasher.finish())
+    }
+}
+
+impl Circuit {
+    pub fn is_visible_to(&self, username: Option<&str>, can_see_private: bool) -> bool {
+        self.visibility == CircuitVisibility::Public
+            || can_see_private
+            || username.is_some_and(|u| u == self.owner)
+    }
+
+    pub fn remove_problem(&mut self, problem_id: u32) {
+        self.problem_ids.retain(|&p| p != problem_id);
+        for completed in self.progress.values_mut() {
+            completed.retain(|&p| p != problem_id);
+        }
+    }
+
+    pub fn to_detail(&self) -> CircuitDetail {
+        CircuitDetail {
+            id: self.id,
+            name: self.name.clone(),
+            owner: self.owner.clone(),
+            problem_ids: self.problem_ids.clone(),
+            visibility: self.visibility,
             updated_at: self.updated_at.clone(),
         }
     }
+
+    pub fn progress_of(&self, username: &str) -> CircuitProgress {
+        let completed = self.progress.get(username).cloned().unwrap_or_default();
+        let next_problem_id = self
+            .problem_ids
+            .iter()
+            .find(|id| !completed.contains(id))
+            .copied();
+        CircuitProgress {
+            circuit_id: self.id,
+            completed,
+            total: self.problem_ids.len() as u32,
+            next_problem_id,
+        }
+    }
+}
+
+impl InviteCode {
+    pub fn is_valid(&self, now: u64) -> bool {
+        self.uses < self.max_uses
+            && self
+                .expires_at
+                .as_ref()
+                .and_then(|e| e.parse::<u64>().ok())
+                .is_none_or(|expires_at| now < expires_at)
+    }
 }
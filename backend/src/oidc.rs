@@ -0,0 +1,341 @@
+use axum::http::{HeaderMap, header};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode, decode_header, jwk::JwkSet};
+use rand::Rng;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use url::Url;
+
+use crate::auth::generate_token;
+use crate::error::ApiError;
+use crate::models::OidcSettings;
+
+// How long the user has to finish logging in at the provider
+const PENDING_LOGIN_LIFETIME: Duration = Duration::from_secs(10 * 60);
+const MAX_PENDING_LOGINS: usize = 1000;
+// Binds the `state` to the browser that started the login, against login CSRF
+const STATE_COOKIE: &str = "oidc_state";
+const STATE_COOKIE_PATH: &str = "/api/v1/auth/oidc";
+
+#[derive(Debug, Clone, Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct IdTokenClaims {
+    sub: String,
+    nonce: Option<String>,
+    #[serde(flatten)]
+    other: HashMap<String, serde_json::Value>,
+}
+
+struct PendingLogin {
+    code_verifier: String,
+    nonce: String,
+    created: Instant,
+}
+
+// Verified identity from the provider
+pub struct OidcIdentity {
+    pub subject: String,
+    pub username: String,
+}
+
+#[derive(Debug)]
+pub enum OidcError {
+    // Unknown or expired `state`, e.g. the callback was opened twice or in another browser
+    InvalidState,
+    // The provider refused the login, e.g. the user cancelled
+    Denied(String),
+    // The provider could not be reached or returned something invalid
+    Provider(String),
+    MissingUsername,
+}
+
+impl From<OidcError> for ApiError {
+    fn from(error: OidcError) -> Self {
+        match error {
+            OidcError::InvalidState => {
+                ApiError::bad_request("INVALID_OIDC_STATE", "Login expired, please try again")
+            }
+            OidcError::Denied(reason) => {
+                ApiError::forbidden("OIDC_DENIED", format!("Login was denied: {reason}"))
+            }
+            OidcError::Provider(reason) => {
+                eprintln!("OIDC provider error: {reason}");
+                ApiError::bad_gateway("OIDC_PROVIDER_ERROR", "Identity provider error")
+            }
+            OidcError::MissingUsername => ApiError::bad_gateway(
+                "OIDC_PROVIDER_ERROR",
+                "Identity provider did not return a username",
+            ),
+        }
+    }
+}
+
+fn provider_error(e: impl std::fmt::Display) -> OidcError {
+    OidcError::Provider(e.to_string())
+}
+
+// Authorization code flow with PKCE against the provider configured in settings
+pub struct OidcClient {
+    settings: OidcSettings,
+    http: reqwest::Client,
+    discovery: RwLock<Option<Discovery>>,
+    jwks: RwLock<JwkSet>,
+    pending: RwLock<HashMap<String, PendingLogin>>,
+}
+
+impl OidcClient {
+    pub fn new(settings: OidcSettings) -> Self {
+        Self {
+            settings,
+            http: reqwest::Client::new(),
+            discovery: RwLock::new(None),
+            jwks: RwLock::new(JwkSet { keys: Vec::new() }),
+            pending: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn settings(&self) -> &OidcSettings {
+        &self.settings
+    }
+
+    // Fetched on first use, so the server starts even if the provider is down
+    async fn discovery(&self) -> Result<Discovery, OidcError> {
+        if let Some(discovery) = self.discovery.read().await.as_ref() {
+            return Ok(discovery.clone());
+        }
+
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            self.settings.issuer.trim_end_matches('/')
+        );
+        let discovery: Discovery = self
+            .http
+            .get(&url)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(provider_error)?
+            .json()
+            .await
+            .map_err(provider_error)?;
+
+        *self.discovery.write().await = Some(discovery.clone());
+        Ok(discovery)
+    }
+
+    // URL of the provider's login page and the fresh `state` the PKCE verifier is remembered under
+    pub async fn authorization_url(&self) -> Result<(String, String), OidcError> {
+        let discovery = self.discovery().await?;
+
+        let code_verifier = URL_SAFE_NO_PAD.encode(rand::thread_rng().r#gen::<[u8; 32]>());
+        let code_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()));
+        let state = generate_token();
+        let nonce = generate_token();
+
+        let mut url = Url::parse(&discovery.authorization_endpoint).map_err(provider_error)?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.settings.client_id)
+            .append_pair("redirect_uri", &self.settings.redirect_uri)
+            .append_pair("scope", &self.settings.scopes.join(" "))
+            .append_pair("state", &state)
+            .append_pair("nonce", &nonce)
+            .append_pair("code_challenge", &code_challenge)
+            .append_pair("code_challenge_method", "S256");
+
+        let mut pending = self.pending.write().await;
+        pending.retain(|_, login| login.created.elapsed() < PENDING_LOGIN_LIFETIME);
+        if pending.len() >= MAX_PENDING_LOGINS
+            && let Some(oldest) = pending
+                .iter()
+                .min_by_key(|(_, login)| login.created)
+                .map(|(state, _)| state.clone())
+        {
+            pending.remove(&oldest);
+        }
+        pending.insert(
+            state.clone(),
+            PendingLogin {
+                code_verifier,
+                nonce,
+                created: Instant::now(),
+            },
+        );
+
+        Ok((url.into(), state))
+    }
+
+    // Set-Cookie value remembering the `state` in the browser until the callback
+    pub fn state_cookie(&self, state: &str) -> String {
+        self.cookie(state, PENDING_LOGIN_LIFETIME.as_secs())
+    }
+
+    pub fn clear_state_cookie(&self) -> String {
+        self.cookie("", 0)
+    }
+
+    fn cookie(&self, value: &str, max_age: u64) -> String {
+        // Lax still sends the cookie on the provider's top level redirect back to us
+        let mut cookie = format!(
+            "{STATE_COOKIE}={value}; Path={STATE_COOKIE_PATH}; Max-Age={max_age}; HttpOnly; SameSite=Lax"
+        );
+        if self.settings.redirect_uri.starts_with("https://") {
+            cookie.push_str("; Secure");
+        }
+        cookie
+    }
+
+    // Checks the `state` of the callback against the cookie set when the login started
+    pub fn check_state_cookie(&self, headers: &HeaderMap, state: &str) -> Result<(), OidcError> {
+        let cookie = headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|h| h.to_str().ok())
+            .flat_map(|h| h.split(';'))
+            .find_map(|pair| {
+                let (name, value) = pair.split_once('=')?;
+                (name.trim() == STATE_COOKIE).then(|| value.trim())
+            });
+        match cookie {
+            Some(cookie) if !cookie.is_empty() && cookie == state => Ok(()),
+            _ => Err(OidcError::InvalidState),
+        }
+    }
+
+    // Redeems the code from the callback and verifies the returned ID token
+    pub async fn exchange(&self, code: &str, state: &str) -> Result<OidcIdentity, OidcError> {
+        let login = self
+            .pending
+            .write()
+            .await
+            .remove(state)
+            .filter(|login| login.created.elapsed() < PENDING_LOGIN_LIFETIME)
+            .ok_or(OidcError::InvalidState)?;
+
+        let discovery = self.discovery().await?;
+
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.settings.redirect_uri),
+            ("client_id", &self.settings.client_id),
+            ("code_verifier", &login.code_verifier),
+        ];
+        if let Some(secret) = &self.settings.client_secret {
+            form.push(("client_secret", secret));
+        }
+
+        let response = self
+            .http
+            .post(&discovery.token_endpoint)
+            .form(&form)
+            .send()
+            .await
+            .map_err(provider_error)?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(OidcError::Provider(format!(
+                "token endpoint returned {status}: {body}"
+            )));
+        }
+        let tokens: TokenResponse = response.json().await.map_err(provider_error)?;
+
+        let claims = self.verify_id_token(&tokens.id_token, &discovery).await?;
+        if claims.nonce.as_deref() != Some(login.nonce.as_str()) {
+            return Err(OidcError::Provider("ID token nonce mismatch".to_string()));
+        }
+
+        let username = match self.settings.username_claim.as_str() {
+            "sub" => Some(claims.sub.clone()),
+            claim => claims
+                .other
+                .get(claim)
+                .and_then(|v| v.as_str())
+                .map(str::to_string),
+        }
+        .ok_or(OidcError::MissingUsername)?;
+
+        Ok(OidcIdentity {
+            subject: claims.sub,
+            username,
+        })
+    }
+
+    async fn verify_id_token(
+        &self,
+        id_token: &str,
+        discovery: &Discovery,
+    ) -> Result<IdTokenClaims, OidcError> {
+        let header = decode_header(id_token).map_err(provider_error)?;
+
+        // Only the provider's public keys are trusted, never a shared secret
+        if matches!(
+            header.alg,
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+        ) {
+            return Err(OidcError::Provider(format!(
+                "unsupported ID token algorithm {:?}",
+                header.alg
+            )));
+        }
+
+        let key = self
+            .decoding_key(header.kid.as_deref(), &discovery.jwks_uri)
+            .await?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&discovery.issuer]);
+        validation.set_audience(&[&self.settings.client_id]);
+
+        let data = decode::<IdTokenClaims>(id_token, &key, &validation).map_err(provider_error)?;
+        Ok(data.claims)
+    }
+
+    // Refetches the key set when the key is unknown, providers rotate their keys
+    async fn decoding_key(
+        &self,
+        kid: Option<&str>,
+        jwks_uri: &str,
+    ) -> Result<DecodingKey, OidcError> {
+        let find = |jwks: &JwkSet| match kid {
+            Some(kid) => jwks.find(kid).cloned(),
+            None => jwks.keys.first().cloned(),
+        };
+
+        if let Some(jwk) = find(&*self.jwks.read().await) {
+            return DecodingKey::from_jwk(&jwk).map_err(provider_error);
+        }
+
+        let jwks: JwkSet = self
+            .http
+            .get(jwks_uri)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(provider_error)?
+            .json()
+            .await
+            .map_err(provider_error)?;
+        let jwk = find(&jwks);
+        *self.jwks.write().await = jwks;
+
+        let jwk = jwk.ok_or_else(|| OidcError::Provider("unknown ID token key".to_string()))?;
+        DecodingKey::from_jwk(&jwk).map_err(provider_error)
+    }
+}
@@ -0,0 +1,169 @@
+use serde::{Serialize, Serializer};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::{Deref, DerefMut};
+
+use crate::models::Problem;
+
+// Problems by ID, with indexes by sector and author. All iteration is in ID order.
+// Problems are only changed through `get_mut`, `update_all` and `retain`, which keep the
+// indexes up to date.
+pub struct ProblemStore {
+    problems: BTreeMap<u32, Problem>,
+    by_sector: HashMap<u16, BTreeSet<u32>>,
+    by_author: HashMap<String, BTreeSet<u32>>,
+}
+
+impl ProblemStore {
+    pub fn new(problems: Vec<Problem>) -> Self {
+        let mut store = Self {
+            problems: BTreeMap::new(),
+            by_sector: HashMap::new(),
+            by_author: HashMap::new(),
+        };
+        for problem in problems {
+            store.insert(problem);
+        }
+        store
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Problem> {
+        self.problems.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: u32) -> Option<ProblemMut<'_>> {
+        let Self {
+            problems,
+            by_sector,
+            by_author,
+        } = self;
+        let problem = problems.get_mut(&id)?;
+        Some(ProblemMut {
+            sector_id: problem.sector_id,
+            author: problem.author.clone(),
+            problem,
+            by_sector,
+            by_author,
+        })
+    }
+
+    pub fn insert(&mut self, mut problem: Problem) {
+        self.remove(problem.id);
+        problem.refresh_stats();
+        index(&mut self.by_sector, problem.sector_id, problem.id);
+        index(&mut self.by_author, problem.author.clone(), problem.id);
+        self.problems.insert(problem.id, problem);
+    }
+
+    pub fn remove(&mut self, id: u32) -> Option<Problem> {
+        let problem = self.problems.remove(&id)?;
+        self.unindex(&problem);
+        Some(problem)
+    }
+
+    pub fn retain(&mut self, mut keep: impl FnMut(&Problem) -> bool) {
+        let removed: Vec<u32> = self
+            .problems
+            .values()
+            .filter(|p| !keep(p))
+            .map(|p| p.id)
+            .collect();
+        for id in removed {
+            self.remove(id);
+        }
+    }
+
+    // Changes every problem, then rebuilds the indexes
+    pub fn update_all(&mut self, f: impl FnMut(&mut Problem)) {
+        self.problems.values_mut().for_each(f);
+        self.by_sector.clear();
+        self.by_author.clear();
+        for problem in self.problems.values() {
+            index(&mut self.by_sector, problem.sector_id, problem.id);
+            index(&mut self.by_author, problem.author.clone(), problem.id);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Problem> {
+        self.problems.values()
+    }
+
+    pub fn in_sector(&self, sector_id: u16) -> impl Iterator<Item = &Problem> {
+        self.lookup(self.by_sector.get(&sector_id))
+    }
+
+    pub fn by_author(&self, author: &str) -> impl Iterator<Item = &Problem> {
+        self.lookup(self.by_author.get(author))
+    }
+
+    fn lookup<'a>(&'a self, ids: Option<&'a BTreeSet<u32>>) -> impl Iterator<Item = &'a Problem> {
+        ids.into_iter()
+            .flatten()
+            .filter_map(|id| self.problems.get(id))
+    }
+
+    fn unindex(&mut self, problem: &Problem) {
+        unindex(&mut self.by_sector, &problem.sector_id, problem.id);
+        unindex(&mut self.by_author, &problem.author, problem.id);
+    }
+}
+
+fn index<K: Eq + std::hash::Hash>(index: &mut HashMap<K, BTreeSet<u32>>, key: K, id: u32) {
+    index.entry(key).or_default().insert(id);
+}
+
+fn unindex<K, Q>(index: &mut HashMap<K, BTreeSet<u32>>, key: &Q, id: u32)
+where
+    K: Eq + std::hash::Hash + std::borrow::Borrow<Q>,
+    Q: Eq + std::hash::Hash + ?Sized,
+{
+    if let Some(ids) = index.get_mut(key) {
+        ids.remove(&id);
+        if ids.is_empty() {
+            index.remove(key);
+        }
+    }
+}
+
+// Saved as a plain list, like before the store existed
+impl Serialize for ProblemStore {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.problems.values())
+    }
+}
+
+// Mutable access to a stored problem, moves it in the indexes when dropped
+pub struct ProblemMut<'a> {
+    problem: &'a mut Problem,
+    by_sector: &'a mut HashMap<u16, BTreeSet<u32>>,
+    by_author: &'a mut HashMap<String, BTreeSet<u32>>,
+    sector_id: u16,
+    author: String,
+}
+
+impl Deref for ProblemMut<'_> {
+    type Target = Problem;
+
+    fn deref(&self) -> &Problem {
+        self.problem
+    }
+}
+
+impl DerefMut for ProblemMut<'_> {
+    fn deref_mut(&mut self) -> &mut Problem {
+        self.problem
+    }
+}
+
+impl Drop for ProblemMut<'_> {
+    fn drop(&mut self) {
+        let id = self.problem.id;
+        if self.problem.sector_id != self.sector_id {
+            unindex(self.by_sector, &self.sector_id, id);
+            index(self.by_sector, self.problem.sector_id, id);
+        }
+        if self.problem.author != self.author {
+            unindex(self.by_author, self.author.as_str(), id);
+            index(self.by_author, self.problem.author.clone(), id);
+        }
+    }
+}
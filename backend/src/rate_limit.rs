@@ -1,80 +1,183 @@
+use axum::{
+    extract::{Request, State},
+    http::{Method, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
 use std::collections::HashMap;
-use std::net::IpAddr;
-use std::time::{Duration, SystemTime};
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::auth::extract_token;
+use crate::client_ip::ClientIp;
+use crate::error::ApiError;
+use crate::models::{
+    AttemptLimits, BucketConfig, BucketKey, Lockout, LockoutKey, LoginAttempts, LoginLimits,
+};
+use crate::state::AppState;
 
-const WAIT_MULTIPLIER: u64 = 3;
-const BAN_THRESHOLD: u32 = 5;
-const BAN_DURATION: u64 = 2 * 60 * 60;
 const CLEANUP_AGE: u64 = 24 * 60 * 60;
 
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
 #[derive(Debug, Clone)]
 struct LoginAttempt {
     count: u32,
-    last_attempt: SystemTime,
+    last_attempt: u64,
 }
 
+// Failed logins per IP and per username, so neither a shared IP nor rotating IPs defeat it
 pub struct RateLimiter {
-    attempts: HashMap<IpAddr, LoginAttempt>,
+    attempts: HashMap<LockoutKey, LoginAttempt>,
+    limits: LoginLimits,
 }
 
 impl RateLimiter {
-    pub fn new() -> Self {
-        Self {
-            attempts: HashMap::new(),
+    pub fn new(limits: LoginLimits, saved: Vec<LoginAttempts>) -> Self {
+        let attempts = saved
+            .into_iter()
+            .map(|a| {
+                (
+                    a.key,
+                    LoginAttempt {
+                        count: a.count,
+                        last_attempt: a.last_attempt,
+                    },
+                )
+            })
+            .collect();
+        Self { attempts, limits }
+    }
+
+    fn limits_for(&self, key: &LockoutKey) -> &AttemptLimits {
+        match key {
+            LockoutKey::Ip(_) => &self.limits.ip,
+            LockoutKey::Username(_) => &self.limits.username,
         }
     }
 
-    pub fn check_and_wait(&mut self, ip: IpAddr) -> Result<(), RateLimitError> {
-        let now = SystemTime::now();
-        self.cleanup_old_entries(now);
+    fn keys(ip: IpAddr, username: &str) -> [LockoutKey; 2] {
+        [
+            LockoutKey::Ip(ip),
+            LockoutKey::Username(username.to_string()),
+        ]
+    }
 
-        if let Some(attempt) = self.attempts.get(&ip) {
-            if attempt.count >= BAN_THRESHOLD {
-                let ban_until = attempt.last_attempt + Duration::from_secs(BAN_DURATION);
-                if now < ban_until {
-                    let remaining = ban_until.duration_since(now).unwrap_or_default();
-                    return Err(RateLimitError::Banned(remaining.as_secs()));
-                }
-            } else if attempt.count > 0 {
-                let wait_duration = Duration::from_secs(WAIT_MULTIPLIER * attempt.count as u64);
-                let can_attempt_at = attempt.last_attempt + wait_duration;
-                if now < can_attempt_at {
-                    let remaining = can_attempt_at.duration_since(now).unwrap_or_default();
-                    return Err(RateLimitError::TooManyAttempts(remaining.as_secs()));
-                }
+    fn check(&self, key: &LockoutKey, now: u64) -> Result<(), RateLimitError> {
+        let Some(attempt) = self.attempts.get(key) else {
+            return Ok(());
+        };
+        let limits = self.limits_for(key);
+
+        if attempt.count >= limits.ban_threshold {
+            let ban_until = attempt.last_attempt + limits.ban_duration;
+            if now < ban_until {
+                return Err(RateLimitError::Banned(ban_until - now));
+            }
+        } else if attempt.count > 0 {
+            let can_attempt_at =
+                attempt.last_attempt + limits.wait_multiplier * attempt.count as u64;
+            if now < can_attempt_at {
+                return Err(RateLimitError::TooManyAttempts(can_attempt_at - now));
             }
         }
 
         Ok(())
     }
 
-    pub fn record_failed_attempt(&mut self, ip: IpAddr) -> u64 {
-        let attempt = self.attempts.entry(ip).or_insert(LoginAttempt {
-            count: 0,
-            last_attempt: SystemTime::now(),
-        });
+    pub fn check_and_wait(&mut self, ip: IpAddr, username: &str) -> Result<(), RateLimitError> {
+        let now = now_secs();
+        self.cleanup_old_entries(now);
+
+        // A ban wins over a wait, otherwise the longer wait
+        Self::keys(ip, username)
+            .iter()
+            .filter_map(|key| self.check(key, now).err())
+            .max_by_key(|e| (e.is_ban(), e.timeout()))
+            .map_or(Ok(()), Err)
+    }
+
+    // Returns seconds until the next attempt is allowed
+    pub fn record_failed_attempt(&mut self, ip: IpAddr, username: &str) -> u64 {
+        let now = now_secs();
+        let mut timeout = 0;
+
+        for key in Self::keys(ip, username) {
+            let limits = *self.limits_for(&key);
+            let attempt = self.attempts.entry(key).or_insert(LoginAttempt {
+                count: 0,
+                last_attempt: now,
+            });
 
-        attempt.count += 1;
-        attempt.last_attempt = SystemTime::now();
+            attempt.count += 1;
+            attempt.last_attempt = now;
 
-        if attempt.count >= BAN_THRESHOLD {
-            BAN_DURATION
-        } else {
-            WAIT_MULTIPLIER * attempt.count as u64
+            timeout = timeout.max(if attempt.count >= limits.ban_threshold {
+                limits.ban_duration
+            } else {
+                limits.wait_multiplier * attempt.count as u64
+            });
         }
+
+        timeout
     }
 
-    pub fn record_successful_attempt(&mut self, ip: IpAddr) {
-        self.attempts.remove(&ip);
+    // Returns whether there was anything to clear
+    pub fn record_successful_attempt(&mut self, ip: IpAddr, username: &str) -> bool {
+        let mut cleared = false;
+        for key in Self::keys(ip, username) {
+            cleared |= self.attempts.remove(&key).is_some();
+        }
+        cleared
     }
 
-    fn cleanup_old_entries(&mut self, now: SystemTime) {
-        self.attempts.retain(|_, attempt| {
-            now.duration_since(attempt.last_attempt)
-                .unwrap_or_default()
-                .as_secs()
-                < CLEANUP_AGE
-        });
+    pub fn lockouts(&self) -> Vec<Lockout> {
+        let now = now_secs();
+        let mut lockouts: Vec<Lockout> = self
+            .attempts
+            .iter()
+            .map(|(key, attempt)| {
+                let locked = self.check(key, now).err();
+                Lockout {
+                    key: key.clone(),
+                    failed_attempts: attempt.count,
+                    last_attempt: attempt.last_attempt.to_string(),
+                    banned: locked.as_ref().is_some_and(RateLimitError::is_ban),
+                    locked_until: locked.map(|e| (now + e.timeout()).to_string()),
+                }
+            })
+            .collect();
+        lockouts.sort_by(|a, b| b.last_attempt.cmp(&a.last_attempt));
+        lockouts
+    }
+
+    // Forgets all failed attempts of the key, returns whether there were any
+    pub fn lift(&mut self, key: &LockoutKey) -> bool {
+        self.attempts.remove(key).is_some()
+    }
+
+    pub fn saved(&self) -> Vec<LoginAttempts> {
+        self.attempts
+            .iter()
+            .map(|(key, attempt)| LoginAttempts {
+                key: key.clone(),
+                count: attempt.count,
+                last_attempt: attempt.last_attempt,
+            })
+            .collect()
+    }
+
+    fn cleanup_old_entries(&mut self, now: u64) {
+        let max_age = CLEANUP_AGE
+            .max(self.limits.ip.ban_duration)
+            .max(self.limits.username.ban_duration);
+        self.attempts
+            .retain(|_, attempt| now.saturating_sub(attempt.last_attempt) < max_age);
     }
 }
 
@@ -101,6 +204,10 @@ impl RateLimitError {
         }
     }
 
+    pub fn is_ban(&self) -> bool {
+        matches!(self, RateLimitError::Banned(_))
+    }
+
     pub fn timeout(&self) -> u64 {
         match self {
             RateLimitError::Banned(secs) => *secs,
@@ -108,3 +215,169 @@ impl RateLimitError {
         }
     }
 }
+
+// Route groups with separate request limits, see `RateLimits`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RouteGroup {
+    Auth,
+    Write,
+    Read,
+}
+
+impl RouteGroup {
+    // None for requests outside the API and CORS preflights, which are never limited
+    pub fn of(method: &Method, path: &str) -> Option<Self> {
+        let path = path.strip_prefix("/api/")?;
+        if *method == Method::OPTIONS {
+            return None;
+        }
+        if *method == Method::GET || *method == Method::HEAD {
+            return Some(RouteGroup::Read);
+        }
+        match path {
+            "v1/auth/register" | "v1/auth/login" | "v1/auth/reset_password" => {
+                Some(RouteGroup::Auth)
+            }
+            _ => Some(RouteGroup::Write),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum BucketId {
+    Ip(IpAddr),
+    User(String),
+}
+
+#[derive(Debug, Clone)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    // Buckets that refilled completely are the same as new ones and can be dropped
+    full_at: Instant,
+}
+
+pub struct RequestLimiter {
+    buckets: HashMap<(RouteGroup, BucketId), Bucket>,
+    last_cleanup: Instant,
+}
+
+impl RequestLimiter {
+    pub fn new() -> Self {
+        Self {
+            buckets: HashMap::new(),
+            last_cleanup: Instant::now(),
+        }
+    }
+
+    // Takes a token from every bucket the request counts against, or none if one is empty
+    pub fn try_acquire(
+        &mut self,
+        group: RouteGroup,
+        config: &BucketConfig,
+        ip: IpAddr,
+        username: Option<&str>,
+    ) -> Result<(), RateLimitError> {
+        let now = Instant::now();
+        self.cleanup_full_buckets(now);
+
+        let user = username.map(|u| BucketId::User(u.to_string()));
+        let ids = match config.key {
+            BucketKey::Ip => vec![BucketId::Ip(ip)],
+            BucketKey::User => vec![user.unwrap_or(BucketId::Ip(ip))],
+            BucketKey::Both => std::iter::once(BucketId::Ip(ip)).chain(user).collect(),
+        };
+
+        let capacity = config.capacity.max(1) as f64;
+        let rate = config.per_minute.max(1) as f64 / 60.0;
+
+        let mut wait: f64 = 0.0;
+        for id in &ids {
+            if let Some(bucket) = self.buckets.get_mut(&(group, id.clone())) {
+                let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+                bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+                bucket.updated = now;
+                if bucket.tokens < 1.0 {
+                    wait = wait.max((1.0 - bucket.tokens) / rate);
+                }
+            }
+        }
+
+        if wait > 0.0 {
+            return Err(RateLimitError::TooManyAttempts(wait.ceil() as u64));
+        }
+
+        for id in ids {
+            let bucket = self.buckets.entry((group, id)).or_insert(Bucket {
+                tokens: capacity,
+                updated: now,
+                full_at: now,
+            });
+            bucket.tokens -= 1.0;
+            bucket.full_at = now + Duration::from_secs_f64((capacity - bucket.tokens) / rate);
+        }
+
+        Ok(())
+    }
+
+    fn cleanup_full_buckets(&mut self, now: Instant) {
+        if now.duration_since(self.last_cleanup).as_secs() < 60 {
+            return;
+        }
+        self.last_cleanup = now;
+        self.buckets.retain(|_, bucket| bucket.full_at > now);
+    }
+}
+
+// Middleware enforcing `Settings::rate_limits` on API routes
+pub async fn limit_requests(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(group) = RouteGroup::of(request.method(), request.uri().path()) else {
+        return next.run(request).await;
+    };
+
+    let limits = &state.settings.rate_limits;
+    let config = match group {
+        RouteGroup::Auth => limits.auth,
+        RouteGroup::Write => limits.write,
+        RouteGroup::Read => limits.read,
+    };
+    let Some(config) = config else {
+        return next.run(request).await;
+    };
+
+    let ip = request
+        .extensions()
+        .get::<ClientIp>()
+        .map(|ClientIp(ip)| *ip)
+        .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+
+    let username = if config.key == BucketKey::Ip {
+        None
+    } else {
+        let auth_header = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|h| h.to_str().ok());
+        match extract_token(auth_header) {
+            Some(token) => state
+                .resolve_token(&token)
+                .await
+                .map(|(username, _)| username),
+            None => None,
+        }
+    };
+
+    let mut limiter = state.request_limiter.write().await;
+    let result = limiter.try_acquire(group, &config, ip, username.as_deref());
+    drop(limiter);
+
+    if let Err(rate_error) = result {
+        return ApiError::from(rate_error).into_response();
+    }
+
+    next.run(request).await
+}
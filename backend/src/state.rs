@@ -1,22 +1,52 @@
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use tokio::sync::RwLock;
 
-use crate::auth::SessionManager;
-use crate::models::{Problem, SectorMetadata, SectorSummary, Settings, User};
-use crate::rate_limit::RateLimiter;
+use crate::audit::AuditLog;
+use crate::auth::{API_TOKEN_PREFIX, SessionManager, hash_api_token};
+use crate::client_ip::{IpNet, parse_trusted_proxies};
+use crate::events::EventBus;
+use crate::models::{
+    AccountDeletion, ApiToken, Circuit, Counters, ForwardedHeader, IdempotencyRecord,
+    IdempotencyRecords, InviteCode, LoginLimits, ModerationLogEntry, Problem, RateLimits,
+    RegistrationMode, Report, Role, SectorMetadata, SectorSummary, Settings, TokenScope, Tombstone,
+    User,
+};
+#[cfg(feature = "oidc")]
+use crate::oidc::OidcClient;
+use crate::problem_store::ProblemStore;
+use crate::rate_limit::{RateLimiter, RequestLimiter};
 use std::fs::File;
 use std::io::BufReader;
 
 pub struct AppState {
     pub settings: Settings,
     pub users: Arc<RwLock<Vec<User>>>,
-    pub problems: Arc<RwLock<Vec<Problem>>>,
+    pub problems: Arc<RwLock<ProblemStore>>,
     pub sessions: Arc<RwLock<SessionManager>>,
     pub next_problem_id: Arc<RwLock<u32>>,
+    pub tombstones: Arc<RwLock<Vec<Tombstone>>>,
+    pub idempotency_records: Arc<RwLock<IdempotencyRecords>>,
+    change_seq: Arc<AtomicU64>,
+    pub events: EventBus,
+    pub next_comment_id: Arc<RwLock<u32>>,
+    pub circuits: Arc<RwLock<Vec<Circuit>>>,
+    pub next_circuit_id: Arc<RwLock<u32>>,
+    pub reports: Arc<RwLock<Vec<Report>>>,
+    pub next_report_id: Arc<RwLock<u32>>,
+    pub moderation_log: Arc<RwLock<Vec<ModerationLogEntry>>>,
+    pub audit_log: Arc<AuditLog>,
+    pub invites: Arc<RwLock<Vec<InviteCode>>>,
+    pub api_tokens: Arc<RwLock<Vec<ApiToken>>>,
+    pub next_api_token_id: Arc<RwLock<u32>>,
     pub dirty: Arc<RwLock<bool>>,
     pub rate_limiter: Arc<RwLock<RateLimiter>>,
+    pub request_limiter: Arc<RwLock<RequestLimiter>>,
+    pub trusted_proxies: Arc<Vec<IpNet>>,
+    #[cfg(feature = "oidc")]
+    pub oidc: Option<Arc<OidcClient>>,
     data_path: PathBuf,
     pub sectors_path: PathBuf,
     pub sectors: Vec<SectorSummary>,
@@ -33,10 +63,17 @@ impl AppState {
             ap_name: "AscendoTrainBoard".to_string(),
             ap_password: "plezaj-gor".to_string(),
             admin_users: vec![],
+            registration_mode: RegistrationMode::Open,
+            account_deletion: AccountDeletion::Anonymize,
+            rate_limits: RateLimits::default(),
+            login_limits: LoginLimits::default(),
+            trusted_proxies: vec![],
+            forwarded_header: ForwardedHeader::default(),
+            oidc: None,
         };
         let settings = if settings_path.exists() {
             match tokio::fs::read_to_string(&settings_path).await {
-                Ok(data) => serde_json::from_str(&data).unwrap_or_else(|_| default_settings),
+                Ok(data) => serde_json::from_str(&data).unwrap_or(default_settings),
                 Err(e) => {
                     eprintln!("Error reading settings.json: {e}");
                     default_settings
@@ -47,7 +84,7 @@ impl AppState {
         };
 
         let users_path = data_path.join("users.json");
-        let users = if users_path.exists() {
+        let users: Vec<User> = if users_path.exists() {
             match tokio::fs::read_to_string(&users_path).await {
                 Ok(data) => serde_json::from_str(&data).unwrap_or_else(|_| Vec::new()),
                 Err(e) => {
@@ -60,7 +97,7 @@ impl AppState {
         };
 
         let problems_path = data_path.join("problems.json");
-        let problems: Vec<Problem> = if problems_path.exists() {
+        let mut problems: Vec<Problem> = if problems_path.exists() {
             match tokio::fs::read_to_string(&problems_path).await {
                 Ok(data) => serde_json::from_str(&data).unwrap_or_else(|_| Vec::new()),
                 Err(_) => Vec::new(),
@@ -69,7 +106,146 @@ impl AppState {
             Vec::new()
         };
 
-        let next_id = problems.iter().map(|p| p.id).max().unwrap_or(0) + 1;
+        let mut favorite_counts: HashMap<u32, u32> = HashMap::new();
+        for id in users.iter().flat_map(|u| &u.favorites) {
+            *favorite_counts.entry(*id).or_insert(0) += 1;
+        }
+        for problem in &mut problems {
+            problem.stats.favorite_count = favorite_counts.get(&problem.id).copied().unwrap_or(0);
+        }
+
+        let tombstones_path = data_path.join("tombstones.json");
+        let tombstones: Vec<Tombstone> = if tombstones_path.exists() {
+            match tokio::fs::read_to_string(&tombstones_path).await {
+                Ok(data) => serde_json::from_str(&data).unwrap_or_else(|_| Vec::new()),
+                Err(_) => Vec::new(),
+            }
+        } else {
+            Vec::new()
+        };
+
+        let idempotency_records_path = data_path.join("idempotency_keys.json");
+        let idempotency_records: Vec<IdempotencyRecord> = if idempotency_records_path.exists() {
+            match tokio::fs::read_to_string(&idempotency_records_path).await {
+                Ok(data) => serde_json::from_str(&data).unwrap_or_else(|_| Vec::new()),
+                Err(_) => Vec::new(),
+            }
+        } else {
+            Vec::new()
+        };
+        let idempotency_records: IdempotencyRecords = idempotency_records
+            .into_iter()
+            .map(|r| ((r.username.clone(), r.idempotency_key.clone()), r))
+            .collect();
+
+        // Ids of deleted problems aren't reused, clients may still know them
+        let next_id = problems
+            .iter()
+            .map(|p| p.id)
+            .chain(tombstones.iter().map(|t| t.problem_id))
+            .max()
+            .unwrap_or(0)
+            + 1;
+        let change_seq = problems
+            .iter()
+            .map(|p| p.changed_seq)
+            .chain(tombstones.iter().map(|t| t.seq))
+            .max()
+            .unwrap_or(0);
+        let counters_path = data_path.join("counters.json");
+        let counters: Counters = if counters_path.exists() {
+            match tokio::fs::read_to_string(&counters_path).await {
+                Ok(data) => serde_json::from_str(&data).unwrap_or_default(),
+                Err(_) => Counters::default(),
+            }
+        } else {
+            Counters::default()
+        };
+        // Ids of deleted comments aren't reused, reports may still point at them
+        let next_comment_id = problems
+            .iter()
+            .flat_map(|p| &p.comments)
+            .map(|c| c.id + 1)
+            .chain([counters.next_comment_id, 1])
+            .max()
+            .unwrap_or(1);
+
+        let circuits_path = data_path.join("circuits.json");
+        let circuits: Vec<Circuit> = if circuits_path.exists() {
+            match tokio::fs::read_to_string(&circuits_path).await {
+                Ok(data) => serde_json::from_str(&data).unwrap_or_else(|_| Vec::new()),
+                Err(_) => Vec::new(),
+            }
+        } else {
+            Vec::new()
+        };
+
+        let next_circuit_id = circuits.iter().map(|c| c.id).max().unwrap_or(0) + 1;
+
+        let reports_path = data_path.join("reports.json");
+        let reports: Vec<Report> = if reports_path.exists() {
+            match tokio::fs::read_to_string(&reports_path).await {
+                Ok(data) => serde_json::from_str(&data).unwrap_or_else(|_| Vec::new()),
+                Err(_) => Vec::new(),
+            }
+        } else {
+            Vec::new()
+        };
+
+        let next_report_id = reports.iter().map(|r| r.id).max().unwrap_or(0) + 1;
+
+        let moderation_log_path = data_path.join("moderation_log.json");
+        let moderation_log = if moderation_log_path.exists() {
+            match tokio::fs::read_to_string(&moderation_log_path).await {
+                Ok(data) => serde_json::from_str(&data).unwrap_or_else(|_| Vec::new()),
+                Err(_) => Vec::new(),
+            }
+        } else {
+            Vec::new()
+        };
+
+        let audit_log = AuditLog::load(data_path.join("audit_log.jsonl")).await;
+
+        let invites_path = data_path.join("invites.json");
+        let invites = if invites_path.exists() {
+            match tokio::fs::read_to_string(&invites_path).await {
+                Ok(data) => serde_json::from_str(&data).unwrap_or_else(|_| Vec::new()),
+                Err(_) => Vec::new(),
+            }
+        } else {
+            Vec::new()
+        };
+
+        let api_tokens_path = data_path.join("api_tokens.json");
+        let api_tokens: Vec<ApiToken> = if api_tokens_path.exists() {
+            match tokio::fs::read_to_string(&api_tokens_path).await {
+                Ok(data) => serde_json::from_str(&data).unwrap_or_else(|_| Vec::new()),
+                Err(_) => Vec::new(),
+            }
+        } else {
+            Vec::new()
+        };
+
+        let next_api_token_id = api_tokens.iter().map(|t| t.id).max().unwrap_or(0) + 1;
+
+        let lockouts_path = data_path.join("lockouts.json");
+        let lockouts = if lockouts_path.exists() {
+            match tokio::fs::read_to_string(&lockouts_path).await {
+                Ok(data) => serde_json::from_str(&data).unwrap_or_else(|_| Vec::new()),
+                Err(_) => Vec::new(),
+            }
+        } else {
+            Vec::new()
+        };
+        let rate_limiter = RateLimiter::new(settings.login_limits.clone(), lockouts);
+        let trusted_proxies = parse_trusted_proxies(&settings.trusted_proxies);
+
+        #[cfg(feature = "oidc")]
+        let oidc = settings.oidc.clone().map(|s| Arc::new(OidcClient::new(s)));
+        #[cfg(not(feature = "oidc"))]
+        if settings.oidc.is_some() {
+            eprintln!("OIDC is configured, but the server was built without the oidc feature");
+        }
 
         let (sectors, sector_metadata) = Self::load_sectors(&sectors_path)
             .await
@@ -78,11 +254,29 @@ impl AppState {
         Ok(Self {
             settings,
             users: Arc::new(RwLock::new(users)),
-            problems: Arc::new(RwLock::new(problems)),
+            problems: Arc::new(RwLock::new(ProblemStore::new(problems))),
             sessions: Arc::new(RwLock::new(SessionManager::new())),
             next_problem_id: Arc::new(RwLock::new(next_id)),
+            tombstones: Arc::new(RwLock::new(tombstones)),
+            idempotency_records: Arc::new(RwLock::new(idempotency_records)),
+            change_seq: Arc::new(AtomicU64::new(change_seq)),
+            events: EventBus::new(),
+            next_comment_id: Arc::new(RwLock::new(next_comment_id)),
+            circuits: Arc::new(RwLock::new(circuits)),
+            next_circuit_id: Arc::new(RwLock::new(next_circuit_id)),
+            reports: Arc::new(RwLock::new(reports)),
+            next_report_id: Arc::new(RwLock::new(next_report_id)),
+            moderation_log: Arc::new(RwLock::new(moderation_log)),
+            audit_log: Arc::new(audit_log),
+            invites: Arc::new(RwLock::new(invites)),
+            api_tokens: Arc::new(RwLock::new(api_tokens)),
+            next_api_token_id: Arc::new(RwLock::new(next_api_token_id)),
             dirty: Arc::new(RwLock::new(false)),
-            rate_limiter: Arc::new(RwLock::new(RateLimiter::new())),
+            rate_limiter: Arc::new(RwLock::new(rate_limiter)),
+            request_limiter: Arc::new(RwLock::new(RequestLimiter::new())),
+            trusted_proxies: Arc::new(trusted_proxies),
+            #[cfg(feature = "oidc")]
+            oidc,
             data_path,
             sectors_path,
             sectors,
@@ -247,19 +441,173 @@ impl AppState {
         let problems_json = serde_json::to_string(&*problems)?;
         tokio::fs::write(self.data_path.join("problems.json"), problems_json).await?;
 
+        let tombstones = self.tombstones.read().await;
+        let tombstones_json = serde_json::to_string(&*tombstones)?;
+        tokio::fs::write(self.data_path.join("tombstones.json"), tombstones_json).await?;
+
+        let counters = Counters {
+            next_comment_id: *self.next_comment_id.read().await,
+        };
+        let counters_json = serde_json::to_string(&counters)?;
+        tokio::fs::write(self.data_path.join("counters.json"), counters_json).await?;
+
+        // Saved as a plain list, expired records are dropped here
+        let mut idempotency_records = self.idempotency_records.write().await;
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        idempotency_records.retain(|_, r| !r.is_expired(now));
+        let idempotency_records: Vec<_> = idempotency_records.values().collect();
+        let idempotency_records_json = serde_json::to_string(&idempotency_records)?;
+        tokio::fs::write(
+            self.data_path.join("idempotency_keys.json"),
+            idempotency_records_json,
+        )
+        .await?;
+
+        let circuits = self.circuits.read().await;
+        let circuits_json = serde_json::to_string(&*circuits)?;
+        tokio::fs::write(self.data_path.join("circuits.json"), circuits_json).await?;
+
+        let reports = self.reports.read().await;
+        let reports_json = serde_json::to_string(&*reports)?;
+        tokio::fs::write(self.data_path.join("reports.json"), reports_json).await?;
+
+        let moderation_log = self.moderation_log.read().await;
+        let moderation_log_json = serde_json::to_string(&*moderation_log)?;
+        tokio::fs::write(
+            self.data_path.join("moderation_log.json"),
+            moderation_log_json,
+        )
+        .await?;
+
+        let invites = self.invites.read().await;
+        let invites_json = serde_json::to_string(&*invites)?;
+        tokio::fs::write(self.data_path.join("invites.json"), invites_json).await?;
+
+        let api_tokens = self.api_tokens.read().await;
+        let api_tokens_json = serde_json::to_string(&*api_tokens)?;
+        tokio::fs::write(self.data_path.join("api_tokens.json"), api_tokens_json).await?;
+
+        let lockouts = self.rate_limiter.read().await.saved();
+        let lockouts_json = serde_json::to_string(&lockouts)?;
+        tokio::fs::write(self.data_path.join("lockouts.json"), lockouts_json).await?;
+
         Ok(())
     }
 
+    // Users listed in settings.json are always admins, regardless of their stored role
     pub fn is_admin(&self, username: &str) -> bool {
         self.settings.admin_users.contains(&username.to_string())
     }
 
+    // Username of a session or API token, with the scope for API tokens.
+    // API tokens of banned users are rejected, as they don't go through login.
+    pub async fn resolve_token(&self, token: &str) -> Option<(String, Option<TokenScope>)> {
+        let sessions = self.sessions.read().await;
+        if let Some(username) = sessions.get_username(token) {
+            return Some((username.clone(), None));
+        }
+        drop(sessions);
+
+        if !token.starts_with(API_TOKEN_PREFIX) {
+            return None;
+        }
+
+        let token_hash = hash_api_token(token);
+        let mut api_tokens = self.api_tokens.write().await;
+        let api_token = api_tokens.iter_mut().find(|t| t.token_hash == token_hash)?;
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        api_token.last_used_at = Some(now.to_string());
+        let (username, scope) = (api_token.owner.clone(), api_token.scope);
+        drop(api_tokens);
+
+        let users = self.users.read().await;
+        users
+            .iter()
+            .any(|u| u.username == username && !u.banned)
+            .then_some((username, Some(scope)))
+    }
+
+    pub async fn role_of(&self, username: &str) -> Role {
+        if self.is_admin(username) {
+            return Role::Admin;
+        }
+
+        let users = self.users.read().await;
+        users
+            .iter()
+            .find(|u| u.username == username)
+            .map(|u| u.role)
+            .unwrap_or_default()
+    }
+
+    // Next value of the change sequence behind `/sync`. Only call while holding the
+    // problems write lock, so changes become visible in the order of their sequence.
+    pub fn next_change_seq(&self) -> u64 {
+        self.change_seq.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    pub fn change_seq(&self) -> u64 {
+        self.change_seq.load(Ordering::SeqCst)
+    }
+
+    // Leaves tombstones for deleted problems, also only while holding the problems write lock
+    pub async fn add_tombstones(&self, problem_ids: &[u32]) {
+        let deleted_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            .to_string();
+
+        let mut tombstones = self.tombstones.write().await;
+        for &problem_id in problem_ids {
+            tombstones.push(Tombstone {
+                problem_id,
+                seq: self.next_change_seq(),
+                deleted_at: deleted_at.clone(),
+            });
+        }
+    }
+
     pub async fn get_next_problem_id(&self) -> u32 {
         let mut next_id = self.next_problem_id.write().await;
         let id = *next_id;
         *next_id += 1;
         id
     }
+
+    pub async fn get_next_comment_id(&self) -> u32 {
+        let mut next_id = self.next_comment_id.write().await;
+        let id = *next_id;
+        *next_id += 1;
+        id
+    }
+
+    pub async fn get_next_report_id(&self) -> u32 {
+        let mut next_id = self.next_report_id.write().await;
+        let id = *next_id;
+        *next_id += 1;
+        id
+    }
+
+    pub async fn get_next_api_token_id(&self) -> u32 {
+        let mut next_id = self.next_api_token_id.write().await;
+        let id = *next_id;
+        *next_id += 1;
+        id
+    }
+
+    pub async fn get_next_circuit_id(&self) -> u32 {
+        let mut next_id = self.next_circuit_id.write().await;
+        let id = *next_id;
+        *next_id += 1;
+        id
+    }
 }
 
 impl Clone for AppState {
@@ -270,8 +618,26 @@ impl Clone for AppState {
             problems: Arc::clone(&self.problems),
             sessions: Arc::clone(&self.sessions),
             next_problem_id: Arc::clone(&self.next_problem_id),
+            tombstones: Arc::clone(&self.tombstones),
+            idempotency_records: Arc::clone(&self.idempotency_records),
+            change_seq: Arc::clone(&self.change_seq),
+            events: self.events.clone(),
+            next_comment_id: Arc::clone(&self.next_comment_id),
+            circuits: Arc::clone(&self.circuits),
+            next_circuit_id: Arc::clone(&self.next_circuit_id),
+            reports: Arc::clone(&self.reports),
+            next_report_id: Arc::clone(&self.next_report_id),
+            moderation_log: Arc::clone(&self.moderation_log),
+            audit_log: Arc::clone(&self.audit_log),
+            invites: Arc::clone(&self.invites),
+            api_tokens: Arc::clone(&self.api_tokens),
+            next_api_token_id: Arc::clone(&self.next_api_token_id),
             dirty: Arc::clone(&self.dirty),
             rate_limiter: Arc::clone(&self.rate_limiter),
+            request_limiter: Arc::clone(&self.request_limiter),
+            trusted_proxies: Arc::clone(&self.trusted_proxies),
+            #[cfg(feature = "oidc")]
+            oidc: self.oidc.clone(),
             data_path: self.data_path.clone(),
             sectors_path: self.sectors_path.clone(),
             sectors: self.sectors.clone(),
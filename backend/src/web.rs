@@ -0,0 +1,138 @@
+#[cfg(feature = "embed-web")]
+use axum::http::{HeaderMap, StatusCode};
+use axum::{
+    extract::Request,
+    http::{HeaderValue, Uri, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::error::ApiError;
+#[cfg(feature = "embed-web")]
+use crate::handlers::if_none_match;
+
+const INDEX_PATH: &str = "/index.html";
+// Hashed file names change with their content, so they can be cached forever
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+// Everything else is revalidated, so a new release shows up right away
+const NO_CACHE: &str = "no-cache";
+
+// Serves the web app from `page/`: paths without a file extension are routes of the app
+// and get `index.html`, unknown API paths get a JSON error instead
+pub async fn serve_app(mut request: Request, next: Next) -> Response {
+    let path = request.uri().path();
+    if path == "/api" || path.starts_with("/api/") {
+        return ApiError::NotFound("Not found").into_response();
+    }
+
+    let file_name = path.rsplit('/').next().unwrap_or_default();
+    let file_name = if file_name.contains('.') {
+        file_name.to_string()
+    } else {
+        *request.uri_mut() = Uri::from_static(INDEX_PATH);
+        INDEX_PATH.trim_start_matches('/').to_string()
+    };
+
+    let mut response = next.run(request).await;
+    if !response.status().is_success() && !response.status().is_redirection() {
+        return response;
+    }
+
+    let headers = response.headers_mut();
+    let cache_control = if is_hashed(&file_name) {
+        IMMUTABLE
+    } else {
+        NO_CACHE
+    };
+    headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static(cache_control),
+    );
+    // Browsers only compile WebAssembly while streaming it with this type
+    if file_name.ends_with(".wasm") {
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/wasm"),
+        );
+    }
+    response
+}
+
+// Whether the file name carries a content hash, like `8f3a1c2b9d4e.wasm` or `main.8f3a1c2b.js`
+fn is_hashed(file_name: &str) -> bool {
+    let stem = file_name
+        .rsplit_once('.')
+        .map_or(file_name, |(stem, _)| stem);
+    stem.split(['.', '-', '_'])
+        .any(|part| part.len() >= 8 && part.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+// Builds without the Gradle output too, the binary then only serves `page/`
+#[cfg(feature = "embed-web")]
+#[derive(rust_embed::Embed)]
+#[folder = "../app/composeApp/build/dist/wasmJs/productionExecutable"]
+#[allow_missing = true]
+struct EmbeddedApp;
+
+// Whether the Gradle output was there when the binary was built
+#[cfg(feature = "embed-web")]
+pub fn has_embedded_app() -> bool {
+    EmbeddedApp::get(INDEX_PATH.trim_start_matches('/')).is_some()
+}
+
+// Web app built into the binary, serves what isn't found in `page/`
+#[cfg(feature = "embed-web")]
+pub async fn serve_embedded(headers: HeaderMap, uri: Uri) -> Response {
+    let path = uri.path().trim_start_matches('/');
+    let Some(file) = EmbeddedApp::get(path) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let etag = format!("\"{}\"", hex::encode(file.metadata.sha256_hash()));
+    if if_none_match(&headers, &etag) {
+        return (StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response();
+    }
+
+    // Pre-compressed copies are picked up like the ones in `page/`
+    let compressed = [("br", ".br"), ("gzip", ".gz")]
+        .into_iter()
+        .filter(|(encoding, _)| accepts_encoding(&headers, encoding))
+        .find_map(|(encoding, extension)| {
+            EmbeddedApp::get(&format!("{path}{extension}")).map(|file| (encoding, file))
+        });
+
+    let mut response = match compressed {
+        Some((encoding, compressed)) => {
+            ([(header::CONTENT_ENCODING, encoding)], compressed.data).into_response()
+        }
+        None => file.data.into_response(),
+    };
+    let headers = response.headers_mut();
+    if let Ok(content_type) = HeaderValue::from_str(file.metadata.mimetype()) {
+        headers.insert(header::CONTENT_TYPE, content_type);
+    }
+    if let Ok(etag) = HeaderValue::from_str(&etag) {
+        headers.insert(header::ETAG, etag);
+    }
+    headers.insert(header::VARY, HeaderValue::from_static("accept-encoding"));
+    response
+}
+
+#[cfg(feature = "embed-web")]
+fn accepts_encoding(headers: &HeaderMap, encoding: &str) -> bool {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|value| {
+            let mut params = value.split(';').map(str::trim);
+            params.next() == Some(encoding)
+                && params.all(|param| {
+                    param
+                        .strip_prefix("q=")
+                        .and_then(|q| q.parse::<f32>().ok())
+                        .is_none_or(|q| q > 0.0)
+                })
+        })
+}